@@ -1,14 +1,27 @@
+// explicit `return` is the house style
+#![allow(clippy::needless_return)]
+
 use bio::alignment::pairwise::*;
-use bio::alignment::Alignment;
+use bio::alignment::{Alignment, AlignmentOperation};
+use flate2::read::MultiGzDecoder;
+use log::{debug, warn};
 use once_cell::sync::Lazy;
+use pyo3::exceptions::{PyIOError, PyIndexError, PyValueError};
 use pyo3::prelude::*;
+use pyo3::pyclass::CompareOp;
+use rayon::prelude::*;
 use regex::Regex;
+use serde::{Deserialize, Serialize};
 
 use std::cmp;
-use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::io::{BufRead, BufReader, Read};
 extern crate ndarray;
-
-type EnvMap = HashMap<String, String>;
+use ndarray::Array2;
 
 // Tools to quantify methylation in reduced representation bisulfite sequencing reads.
 
@@ -19,8 +32,70 @@ type EnvMap = HashMap<String, String>;
 
 // See https://docs.rs/bio/latest/bio/alignment/pairwise/index.html
 
+// errors from parsing and aligning input files, raised as ValueError in Python
+#[derive(Debug)]
+pub enum QumaError {
+    EmptyGenome,
+    EmptyQuery,
+    EmptyRead,
+    UnexpectedCharacter(char, String),
+    Decode(String),
+    ThreadPool(String),
+    DisallowedCharacters(Vec<(usize, usize, char)>),
+    MatrixSize(usize),
+    EmptyAlphabet,
+    UnknownReference(String),
+}
+
+impl fmt::Display for QumaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            QumaError::EmptyGenome => write!(f, "genome sequence is empty"),
+            QumaError::EmptyQuery => write!(f, "query file contains no reads"),
+            QumaError::EmptyRead => write!(f, "query sequence is empty"),
+            QumaError::UnexpectedCharacter(base, name) => {
+                write!(f, "unexpected character '{}' in read {}", base, name)
+            }
+            QumaError::Decode(reason) => write!(f, "could not decode input: {}", reason),
+            QumaError::ThreadPool(reason) => {
+                write!(f, "could not start alignment threads: {}", reason)
+            }
+            QumaError::MatrixSize(len) => write!(
+                f,
+                "scoring matrix must have {} values, got {}",
+                MATRIX_SIZE * MATRIX_SIZE,
+                len
+            ),
+            QumaError::EmptyAlphabet => write!(f, "allowed alphabet is empty"),
+            QumaError::UnknownReference(name) => write!(f, "unknown reference {}", name),
+            QumaError::DisallowedCharacters(found) => {
+                let positions: Vec<String> = found
+                    .iter()
+                    .map(|(line, column, c)| format!("'{}' at {}:{}", c, line, column))
+                    .collect();
+                write!(
+                    f,
+                    "disallowed characters in genome: {}",
+                    positions.join(", ")
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for QumaError {}
+
+impl From<QumaError> for PyErr {
+    fn from(err: QumaError) -> PyErr {
+        return PyValueError::new_err(err.to_string());
+    }
+}
+
 static ALPHABET: &str = "ACGTURYMWSKDHBVNacgturymwskdhbvn";
 
+// rows and columns of the scoring matrix
+static MATRIX_SIZE: usize = 16;
+
 // matrix alphabet:  ATGCSWRYKMBVHDNU
 // see https://docs.rs/bio/latest/src/bio/scores/blosum62.rs.html#89-94
 static MATRIX: Lazy<ndarray::Array2<i32>> = Lazy::new(|| {
@@ -44,753 +119,7381 @@ static MATRIX: Lazy<ndarray::Array2<i32>> = Lazy::new(|| {
     .unwrap()
 });
 
+// matrix index of the N ambiguity code
+static N_INDEX: usize = 14;
+
+// matrix alphabet in row order
+static MATRIX_ALPHABET: &[u8; 16] = b"ATGCSWRYKMBVHDNU";
+
+// matrix index of every byte, either case, built once and read-only afterwards
+static LOOKUP: Lazy<[Option<usize>; 256]> = Lazy::new(|| {
+    let mut table = [None; 256];
+    for (i, &base) in MATRIX_ALPHABET.iter().enumerate() {
+        table[base as usize] = Some(i);
+        table[base.to_ascii_lowercase() as usize] = Some(i);
+    }
+    table
+});
+
 #[inline]
-fn lookup(a: u8) -> usize {
-    // matrix alphabet:  ATGCSWRYKMBVHDNU
-
-    let mappings = HashMap::from([
-        (b'A', 1),
-        (b'T', 2),
-        (b'G', 3),
-        (b'C', 4),
-        (b'S', 5),
-        (b'W', 6),
-        (b'R', 7),
-        (b'Y', 8),
-        (b'K', 9),
-        (b'M', 10),
-        (b'B', 11),
-        (b'V', 12),
-        (b'H', 13),
-        (b'D', 14),
-        (b'N', 15),
-        (b'U', 16),
-    ]);
-
-    *mappings.get(&a).unwrap() as usize
+fn lookup(a: u8) -> Option<usize> {
+    LOOKUP[a as usize]
+}
+
+// cytosine methylation contexts, defined by the genome sequence.
+// CpG is `CG`, CHG is `C[ACT]G` and CHH is `C[ACT][ACT]`.
+#[pyclass]
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum Context {
+    CpG,
+    CHG,
+    CHH,
+}
+
+impl Context {
+    /// Name of the context as written in reports
+    fn label(&self) -> &'static str {
+        match self {
+            Context::CpG => "CpG",
+            Context::CHG => "CHG",
+            Context::CHH => "CHH",
+        }
+    }
+}
+
+// orientation of a read or genome strand in an alignment.
+// `sign` gives the 1 / -1 encoding of the quma output.
+#[pyclass]
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Direction {
+    Forward,
+    Reverse,
+}
+
+#[pymethods]
+impl Direction {
+    /// Direction as used in quma output
+    ///
+    /// # Returns
+    ///
+    /// * `i32` - 1 for forward, -1 for reverse
+    pub fn sign(&self) -> i32 {
+        match self {
+            Direction::Forward => 1,
+            Direction::Reverse => -1,
+        }
+    }
+}
+
+// pairwise alignment modes for reads against the genome.
+// Global aligns both end to end; Semiglobal aligns the read end to end
+// while the genome may overhang on either side.
+#[pyclass]
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum AlignMode {
+    Local,
+    Global,
+    Semiglobal,
 }
 
 // struct of quma aligment comparison results
 #[pyclass]
-#[derive(Clone)]
-struct QumaResult {
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub struct QumaResult {
+    /// aligned query sequence, gapped with `-`
     #[pyo3(get, set)]
-    q_ali: String,
+    pub q_ali: String,
 
+    /// aligned genome sequence, gapped with `-`
     #[pyo3(get, set)]
-    g_ali: String,
+    pub g_ali: String,
 
+    /// methylation call per site: `1` methylated, `0` unmethylated
     #[pyo3(get, set)]
-    val: String,
+    pub val: String,
 
+    /// percentage of aligned positions that match
     #[pyo3(get, set)]
-    perc: f32,
+    pub perc: f32,
 
+    /// percentage of called sites that are methylated
     #[pyo3(get, set)]
-    pconv: f32,
+    pub pconv: f32,
 
+    /// number of gaps in the more gapped aligned sequence
     #[pyo3(get, set)]
-    gap: i32,
+    pub gap: i32,
 
+    /// number of methylated sites
     #[pyo3(get, set)]
-    menum: i32,
+    pub menum: i32,
 
+    /// number of unmethylated (converted to T) sites
     #[pyo3(get, set)]
-    unconv: i32,
+    pub unconv: i32,
 
+    /// number of methylated (unconverted C) sites
     #[pyo3(get, set)]
-    conv: i32,
+    pub conv: i32,
 
+    /// number of matching aligned positions
     #[pyo3(get, set)]
-    quma_match: i32,
+    pub quma_match: i32,
 
+    /// number of mismatched aligned positions
     #[pyo3(get, set)]
-    ali_mis: i32,
+    pub ali_mis: i32,
 
+    /// length of the alignment
     #[pyo3(get, set)]
-    ali_len: i32,
-}
+    pub ali_len: i32,
 
-// struct to to wrap fasta results
-#[pyclass]
-#[derive(Clone)]
-struct Fasta {
+    /// genome position where the alignment starts
     #[pyo3(get, set)]
-    com: String,
+    pub g_start: i32,
 
+    /// query position where the alignment starts
     #[pyo3(get, set)]
-    pos: String,
+    pub q_start: i32,
 
+    /// number of non-CpG cytosines covered by a C or T
     #[pyo3(get, set)]
-    seq: String,
-}
+    pub conversion_sites: i32,
 
-// struct of quma analysis intermediates.
-// includes fasta sequence, quma results, directon of read, genomic direction,
-// and whether result meets exclusion criteria.
-#[pyclass]
-#[derive(Clone)]
-struct Reference {
+    /// forward genome coordinate of the cytosine of each call in `val`
+    #[pyo3(get, set)]
+    pub cpg_positions: Vec<i32>,
+
+    /// raw score of the pairwise alignment
     #[pyo3(get, set)]
-    fasta: Fasta,
+    pub score: i32,
 
+    /// percentage of informative non-CpG cytosines read as T
     #[pyo3(get, set)]
-    res: QumaResult,
+    pub conv_eff: f32,
 
+    /// alignment midline: `|` identical, `:` C to T conversion, space otherwise
     #[pyo3(get, set)]
-    dir: i32,
+    pub midline: String,
 
+    /// whether the aligned genome region has no site to call, as opposed to
+    /// sites that are all unmethylated
     #[pyo3(get, set)]
-    gdir: i32,
+    pub no_cpg: bool,
 
+    /// alignment columns with a substitution other than a genome C read as T
     #[pyo3(get, set)]
-    exc: i32,
+    pub mismatch_positions: Vec<i32>,
 }
 
-// FIXME: Pickling:  https://github.com/PyO3/pyo3/issues/100
+// floats are hashed by their bits, consistent with field-wise equality
+impl Hash for QumaResult {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.q_ali.hash(state);
+        self.g_ali.hash(state);
+        self.val.hash(state);
+        self.perc.to_bits().hash(state);
+        self.pconv.to_bits().hash(state);
+        self.gap.hash(state);
+        self.menum.hash(state);
+        self.unconv.hash(state);
+        self.conv.hash(state);
+        self.quma_match.hash(state);
+        self.ali_mis.hash(state);
+        self.ali_len.hash(state);
+        self.g_start.hash(state);
+        self.q_start.hash(state);
+        self.conversion_sites.hash(state);
+        self.cpg_positions.hash(state);
+        self.score.hash(state);
+        self.conv_eff.to_bits().hash(state);
+        self.midline.hash(state);
+        self.no_cpg.hash(state);
+        self.mismatch_positions.hash(state);
+    }
+}
 
-// Quma methylation analysis parser for bisulfite conversion DNA sequencing.
-#[pyclass]
-#[allow(dead_code)]
-struct Quma {
-    #[pyo3(get, set)]
-    gfile_contents: String,
+#[pymethods]
+impl QumaResult {
+    fn __richcmp__(&self, other: &Self, op: CompareOp, py: Python<'_>) -> PyObject {
+        match op {
+            CompareOp::Eq => (self == other).into_py(py),
+            CompareOp::Ne => (self != other).into_py(py),
+            _ => py.NotImplemented(),
+        }
+    }
+
+    fn __hash__(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.hash(&mut hasher);
+        return hasher.finish();
+    }
+
+    /// Count dinucleotides in the aligned genomic region
+    ///
+    /// # Returns
+    ///
+    /// * `HashMap<String, i32>` - count of each dinucleotide in `g_ali`
+    fn dinucleotide_counts(&self) -> HashMap<String, i32> {
+        return dinucleotide_counts(&self.g_ali);
+    }
+
+    /// Count substitutions between the aligned genome and read
+    ///
+    /// # Returns
+    ///
+    /// * `Vec<Vec<i32>>` - 4x4 counts with rows the genome base and columns
+    ///   the read base, both in `ACGT` order
+    fn substitution_matrix(&self) -> Vec<Vec<i32>> {
+        let counts = substitution_counts(&self.g_ali, &self.q_ali);
+        return counts.outer_iter().map(|row| row.to_vec()).collect();
+    }
+
+    /// Whether high apparent methylation co-occurs with poor conversion
+    ///
+    /// Unconverted non-CpG cytosines point to failed bisulfite treatment, in
+    /// which case unmethylated CpGs are also read as methylated.
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - true if the methylation calls of the read are suspect
+    fn conversion_suspect(&self) -> bool {
+        let (converted, informative) = non_cpg_conversion(self);
+        if informative == 0 || self.conv + self.unconv == 0 {
+            return false;
+        }
+
+        let conversion = percentage(converted, informative, PercentType::Total).unwrap_or(0.0);
+        return self.pconv >= SUSPECT_METHYLATION && conversion < POOR_CONVERSION;
+    }
+
+    /// Percentage of the called sites of the read that are methylated
+    ///
+    /// Computed from `conv` and `unconv`, so it follows later re-calls.
+    ///
+    /// # Returns
+    ///
+    /// * `f32` - `conv / (conv + unconv)` as a percentage, or 0.0 without calls
+    #[getter]
+    fn methylation_level(&self) -> f32 {
+        return percentage(self.conv, self.unconv, PercentType::Sum).unwrap_or(0.0);
+    }
+
+    fn __repr__(&self) -> String {
+        return format!(
+            "QumaResult(perc={}, menum={}, conv={}, unconv={}, val='{}')",
+            self.perc, self.menum, self.conv, self.unconv, self.val
+        );
+    }
+}
+
+// methylation percentage above which poorly converted reads are suspect
+static SUSPECT_METHYLATION: f32 = 80.0;
 
+// non-CpG conversion percentage below which conversion is considered poor
+static POOR_CONVERSION: f32 = 90.0;
+
+// struct to to wrap fasta results
+#[pyclass]
+#[derive(Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Fasta {
+    /// header line of the record, without the leading `>`
     #[pyo3(get, set)]
-    qfile_contents: String,
+    pub com: String,
 
+    /// 1-based position of the record in the query file
     #[pyo3(get, set)]
-    gseq: String,
+    pub pos: String,
 
+    /// sequence of the record
     #[pyo3(get, set)]
-    qseq: Vec<Fasta>,
+    pub seq: String,
 
+    /// phred+33 base qualities of the record, for fastq input
     #[pyo3(get, set)]
-    gfilep_f: String,
+    pub qual: Option<String>,
 
+    /// bases trimmed from the 5' end of the original read before analysis
     #[pyo3(get, set)]
-    data: Vec<Reference>,
+    pub trim_start: usize,
 
+    /// bases trimmed from the 3' end of the original read before analysis
     #[pyo3(get, set)]
-    values: String,
+    pub trim_end: usize,
 }
 
-/// Create new Quma struct
-///
-/// # Arguments
-///
-/// * `gfile_contents` - genome fasta file contents
-/// * `qfile_contents` - query fasta file contents
-///
-/// # Returns
-///
-/// * `Quma` - Quma struct
 #[pymethods]
-impl Quma {
-    #[new]
-    fn py_new(gfile_contents: String, qfile_contents: String) -> Self {
-        let gseq = parse_genome(&gfile_contents);
-        let qseq = parse_biseq(&qfile_contents);
-        let gfilep_f = fasta_make(&gseq, "genomeF");
-        let data: Vec<Reference> = process_fasta_output(
-            qseq.clone(),
-            String::from("queryF"),
-            String::from("queryR"),
-            gfilep_f.clone(),
-        );
-        let values = format_output(&gseq, &data);
-        return Quma {
-            gfile_contents: gfile_contents,
-            qfile_contents: qfile_contents,
-            gseq: gseq,
-            qseq: qseq,
-            gfilep_f: gfilep_f,
-            data: data,
-            values: values,
-        };
+impl Fasta {
+    fn __richcmp__(&self, other: &Self, op: CompareOp, py: Python<'_>) -> PyObject {
+        match op {
+            CompareOp::Eq => (self == other).into_py(py),
+            CompareOp::Ne => (self != other).into_py(py),
+            _ => py.NotImplemented(),
+        }
     }
 
-    // #[getter]
-    // fn get_values(&self) -> PyResult<String> {
-    //     Ok(self.values.clone())
-    // }
+    fn __hash__(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.hash(&mut hasher);
+        return hasher.finish();
+    }
 
-    // #[getter]
-    // fn get_data(&self) -> PyResult<Vec<Reference>> {
-    //     Ok(self.data.clone())
-    // }
-}
+    /// Convert a position in the analyzed read to the original read
+    ///
+    /// # Arguments
+    ///
+    /// * `pos` - 0-based position in the trimmed read
+    ///
+    /// # Returns
+    ///
+    /// * `usize` - 0-based position in the untrimmed read
+    fn original_position(&self, pos: usize) -> usize {
+        return pos + self.trim_start;
+    }
 
-static RE1: Lazy<Regex> = Lazy::new(|| Regex::new(r"^[\r\s]+").unwrap());
-static RE2: Lazy<Regex> = Lazy::new(|| Regex::new(r"[\r\s]+$").unwrap());
-static RE3: Lazy<Regex> = Lazy::new(|| Regex::new(r"(\r|\n|\r\n){2}").unwrap());
+    /// Sequence of the read with trimmed ends removed, as used for alignment
+    ///
+    /// # Returns
+    ///
+    /// * `String` - analyzed part of `seq`
+    pub fn trimmed_seq(&self) -> String {
+        let end = self.seq.len().saturating_sub(self.trim_end);
+        return self.seq[cmp::min(self.trim_start, end)..end].to_string();
+    }
+}
 
-/// Parse genome file, removing white spaces and extra returns.
-///
-/// # Arguments
-///
-/// * `self` - Quma struct
-///
-/// # Returns
-///
-/// * `string` - parsed and curated string of genome sequence
-fn parse_genome(gfile_contents: &str) -> String {
-    let out_one = RE1.replace_all(&gfile_contents, "");
-    let out_two = RE2.replace_all(&out_one, "");
-    let out_three = RE3.replace_all(&out_two, r"\r|\n|\r\n");
+// struct of quma analysis intermediates.
+// includes fasta sequence, quma results, directon of read, genomic direction,
+// and whether result meets exclusion criteria.
+#[pyclass]
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Reference {
+    /// query record that was aligned
+    #[pyo3(get, set)]
+    pub fasta: Fasta,
 
-    return parse_seq(&out_three);
-}
+    /// results of the best alignment
+    #[pyo3(get, set)]
+    pub res: QumaResult,
 
-static SCRUB1: Lazy<Regex> = Lazy::new(|| Regex::new(r"\r\n\r\n").unwrap());
+    /// read orientation of the best alignment
+    #[pyo3(get, set)]
+    pub dir: Direction,
 
-static SCRUB2: Lazy<Regex> = Lazy::new(|| Regex::new(r"\n\n").unwrap());
+    /// genome strand of the best alignment
+    #[pyo3(get, set)]
+    pub gdir: Direction,
 
-static SCRUB3: Lazy<Regex> = Lazy::new(|| Regex::new(r"\r\r").unwrap());
+    /// 1 if the read meets an exclusion criterion, otherwise 0
+    #[pyo3(get, set)]
+    pub exc: i32,
 
-static SCRUB4: Lazy<Regex> = Lazy::new(|| Regex::new(r"\r\n").unwrap());
+    /// criterion that excluded the read, if any
+    #[pyo3(get, set)]
+    pub reason: Option<String>,
 
-static SCRUB5: Lazy<Regex> = Lazy::new(|| Regex::new(r"\r").unwrap());
+    /// name of the genome reference the read is assigned to
+    #[pyo3(get, set)]
+    pub genome: String,
 
-/// Remove whitespace and repeated newlines.
-///
-/// # Arguments
-///
-/// * `string` - input fasta string
-///
-/// # Returns
-///
-/// * `string` - fasta string with whitespace removed
-fn scrub_whitespace(string: &str) -> String {
-    let trimmed = string.trim();
-    let trimmed = SCRUB1.replace_all(&trimmed, r"\r\n");
-    let trimmed = SCRUB2.replace_all(&trimmed, r"\n");
-    let trimmed = SCRUB3.replace_all(&trimmed, r"\r");
-    let trimmed = SCRUB4.replace_all(&trimmed, r"\n");
-    let trimmed = SCRUB5.replace_all(&trimmed, r"\n");
-    return trimmed.to_string();
+    /// whether the read matched several genome references equally well
+    #[pyo3(get, set)]
+    pub ambiguous_reference: bool,
 }
 
-static CLEAN1: Lazy<Regex> = Lazy::new(|| Regex::new(r"^>").unwrap());
+// struct of per-strand methylation and strand bias flag.
+// strand is the orientation of the read (`dir`) and methylation is pooled
+// over the CpG calls of non-excluded reads on each strand.
+#[pyclass]
+#[derive(Clone)]
+pub struct StrandBias {
+    #[pyo3(get, set)]
+    pub forward_reads: i32,
 
-/// Parse bisulfite sequencing fasta file
-///
-/// # Returns
-///
-/// * `vector` - vector of Fasta structs of sequence reads
-fn parse_biseq(qfile_contents: &str) -> Vec<Fasta> {
-    let multi_clean = scrub_whitespace(&qfile_contents);
-    let mut multi_split = multi_clean.lines();
+    #[pyo3(get, set)]
+    pub reverse_reads: i32,
 
-    let mut outcome = Vec::<Fasta>::new();
+    #[pyo3(get, set)]
+    pub forward_methylation: f32,
 
-    let mut n = 0;
-    let mut tmp_name: Option<&str> = None;
-    let mut tmp_seq: Option<&str> = None;
-    while n < 1 {
-        let line = multi_split.next();
-        match line {
-            Some(a) => match a.starts_with('>') {
-                true => {
-                    tmp_name = Some(a);
-                }
-                false => {
-                    tmp_seq = Some(a);
-                }
-            },
-            None => n += 1,
-        }
-        match (tmp_name, tmp_seq) {
-            (Some(x), Some(y)) => {
-                let processed_x = CLEAN1.replace_all(&x, "");
-                let fa = Fasta {
-                    com: processed_x.to_string(),
-                    pos: String::from(""),
-                    seq: y.to_string(),
-                };
-                outcome.push(fa);
-                tmp_name = None;
-                tmp_seq = None;
-            }
-            _ => continue,
-        }
-    }
+    #[pyo3(get, set)]
+    pub reverse_methylation: f32,
 
-    return outcome;
+    #[pyo3(get, set)]
+    pub z_score: f32,
+
+    #[pyo3(get, set)]
+    pub biased: bool,
 }
 
-static FILE_PATTERNS: Lazy<Regex> = Lazy::new(|| Regex::new(r"^\s*>.*?\n").unwrap());
+// struct of aggregate read counts and methylation across the experiment.
+// methylation is pooled over the CpG calls of non-excluded reads only.
+#[pyclass]
+#[derive(Clone)]
+pub struct Summary {
+    #[pyo3(get, set)]
+    pub total_reads: i32,
 
-/// Extract sequence strings from the string of a text file
-///
-/// # Arguments
-///
-/// * `seq` - string of text file
-///
-/// # Returns
-///
-/// * `string` - sequence string
-fn parse_seq(seq: &str) -> String {
-    let seq = seq.replace("\r\n", "\n").replace("\r", "\n").to_uppercase();
+    #[pyo3(get, set)]
+    pub included_reads: i32,
 
-    // Different file patterns
-    let seq = FILE_PATTERNS.replace_all(&seq, r"");
+    #[pyo3(get, set)]
+    pub excluded_reads: i32,
 
-    // TODO: Did not implement unused fasta patterns
+    #[pyo3(get, set)]
+    pub cpgs_observed: i32,
 
-    return check_char_in_allowed(&seq, ALPHABET);
-}
+    #[pyo3(get, set)]
+    pub methylated: i32,
 
-/// Return only charcters in string present in pattern
-///
-/// # Arguments
-///
-/// * `seq` - sequence string
-/// * `pattern` - string of allowed characters
-///
-/// # Returns
-///
-/// * `string` - sequence string with only allowed characters
-fn check_char_in_allowed(seq: &str, pattern: &str) -> String {
-    return seq.chars().filter(|&p| pattern.contains(p)).collect();
+    #[pyo3(get, set)]
+    pub methylation: f32,
 }
 
-static RE4: Lazy<Regex> = Lazy::new(|| Regex::new(r"[0-9]| |\t|\n|\r|\f").unwrap());
+// Quma methylation analysis parser for bisulfite conversion DNA sequencing.
+// `module` lets pickle find `from_state` when unpickling
+#[pyclass(module = "rust_quma")]
+#[allow(dead_code)]
+#[derive(Serialize, Deserialize)]
+pub struct Quma {
+    #[pyo3(get, set)]
+    pub gfile_contents: String,
 
-/// Write a sequence string to a fasta-formatted text file contents
-///
-/// # Arguments
-///
-/// * `seq` - sequence string
-/// * `seq_name` - name of sequence
-///
-/// # Returns
-///
-/// * `string` - fasta-formatted text file contents
-fn fasta_make(seq: &str, seq_name: &str) -> String {
-    let seq = RE4.replace_all(&seq, "");
-    // let seq = RE5.replace_all(&seq, r"\1\n");
+    #[pyo3(get, set)]
+    pub qfile_contents: String,
 
-    return format!(">{}\n{}", seq_name, seq);
-}
+    /// sequence of the first genome reference
+    #[pyo3(get, set)]
+    pub gseq: String,
 
-/// Process fasta alignment
-///
-/// # Arguments
-///
-/// * `qseq` - vector of Fasta structs of query sequence
-/// * `qfile_f` - query sequence forward read
-/// * `qfile_r` - query sequence reverse complement
-/// * `gfilep_f` - genome sequence forward read
-///
-/// # Returns
-///
-/// * `vector` - vector of Reference structs
-fn process_fasta_output(
-    qseq: Vec<Fasta>,
-    qfile_f: String,
-    qfile_r: String,
-    gfilep_f: String,
-) -> Vec<Reference> {
-    let unconv = 5;
-    let pconv = 95.0;
-    let mis = 10;
-    let perc = 90.0;
+    /// name and sequence of every genome reference
+    #[pyo3(get)]
+    pub genomes: Vec<(String, String)>,
 
-    let mut data = Vec::<Reference>::new();
-    let mut pos = 0;
-    for mut fa in qseq {
-        pos += 1;
-        fa.pos = pos.to_string();
-        let seq_here = fa.seq.clone();
+    #[pyo3(get, set)]
+    pub qseq: Vec<Fasta>,
 
-        let qfile_f_processed = fasta_make(&seq_here, &qfile_f);
-        let qfile_r_processed = fasta_make(&rev_comp(&seq_here), &qfile_r);
+    #[pyo3(get, set)]
+    pub gfilep_f: String,
 
-        let fwd_result = align_seq_and_generate_stats(&qfile_f_processed, &gfilep_f);
-        let rev_result = align_seq_and_generate_stats(&qfile_r_processed, &gfilep_f);
+    #[pyo3(get, set)]
+    pub gfilep_r: String,
 
-        let (this_result, final_direction) = find_best_dataset(fwd_result, rev_result);
+    #[pyo3(get, set)]
+    pub data: Vec<Reference>,
 
-        let genome_direction = 1;
+    #[pyo3(get, set)]
+    pub values: String,
 
-        let mut this_ref = Reference {
-            fasta: fa,
-            res: this_result.clone(),
-            dir: final_direction,
-            gdir: genome_direction,
-            exc: 0,
-        };
+    #[pyo3(get)]
+    pub genome_header: bool,
 
-        if this_result.unconv > unconv {
-            this_ref.exc = 1;
-        } else if this_result.pconv > pconv {
-            this_ref.exc = 1;
-        } else if this_result.ali_mis > mis {
-            this_ref.exc = 1;
-        } else if this_result.perc > perc {
-            this_ref.exc = 1;
-        }
+    #[pyo3(get)]
+    pub contexts: Vec<Context>,
 
-        data.push(this_ref);
-    }
+    #[pyo3(get)]
+    pub clip_penalty: Option<i32>,
 
-    return data;
-}
+    #[pyo3(get)]
+    pub iupac_match: bool,
 
-/// Return reverse complement of sequence
-///
-/// # Arguments
-///
-/// * `seq` - sequence string
-///
-/// # Returns
-///
-/// * `string` - reverse complement of sequence
-fn rev_comp(seq: &str) -> String {
-    let reversed = seq.chars().rev().collect::<String>();
-
-    fn expand_env(value: &str, env_user: &EnvMap) -> String {
-        let mut expanded = value.to_string();
-        for (env_key, env_value) in env_user {
-            expanded = expanded.replace(env_key, env_value);
-        }
-        expanded.to_string()
-    }
-
-    // reverse comp mapping
-    let mut mappings = HashMap::from([
-        ("A".to_string(), "T".to_string()),
-        ("C".to_string(), "G".to_string()),
-        ("G".to_string(), "C".to_string()),
-        ("T".to_string(), "A".to_string()),
-        ("U".to_string(), "A".to_string()),
-        ("R".to_string(), "Y".to_string()),
-        ("Y".to_string(), "R".to_string()),
-        ("M".to_string(), "K".to_string()),
-        ("W".to_string(), "W".to_string()),
-        ("S".to_string(), "S".to_string()),
-        ("K".to_string(), "M".to_string()),
-        ("D".to_string(), "H".to_string()),
-        ("H".to_string(), "D".to_string()),
-        ("B".to_string(), "V".to_string()),
-        ("V".to_string(), "B".to_string()),
-        ("N".to_string(), "N".to_string()),
-        ("a".to_string(), "t".to_string()),
-        ("c".to_string(), "g".to_string()),
-        ("g".to_string(), "c".to_string()),
-        ("t".to_string(), "a".to_string()),
-        ("u".to_string(), "a".to_string()),
-        ("r".to_string(), "y".to_string()),
-        ("y".to_string(), "r".to_string()),
-        ("m".to_string(), "k".to_string()),
-        ("w".to_string(), "w".to_string()),
-        ("s".to_string(), "s".to_string()),
-        ("k".to_string(), "m".to_string()),
-        ("d".to_string(), "h".to_string()),
-        ("h".to_string(), "d".to_string()),
-        ("b".to_string(), "v".to_string()),
-        ("v".to_string(), "b".to_string()),
-        ("n".to_string(), "n".to_string()),
-    ]);
-
-    mappings.insert("$A".to_string(), "Alpha".to_string());
-
-    let value = expand_env(&reversed, &mappings);
-
-    return value;
-}
+    #[pyo3(get)]
+    pub bisulfite_match: bool,
 
-/// Find pairwise alignment substrings
-///
-/// # Arguments
-///
-/// * `alignment` - alignment object
-///
-/// # Returns
-///
-/// * `tuple` - tuple of String, String query and genomic aligned substrings
-fn matching_substrings(
-    alignment: &Alignment,
-    bio_gseq: &[u8],
-    bio_qseq: &[u8],
-) -> (String, String) {
-    let g_substring =
-        String::from_utf8(bio_gseq[alignment.xstart..alignment.xend].to_vec()).unwrap();
-    let q_substring =
-        String::from_utf8(bio_qseq[alignment.ystart..alignment.yend].to_vec()).unwrap();
+    #[pyo3(get)]
+    pub bisulfite_scoring: bool,
 
-    return (g_substring, q_substring);
+    #[pyo3(get)]
+    pub fastq: bool,
+
+    #[pyo3(get)]
+    pub unconv_threshold: i32,
+
+    #[pyo3(get)]
+    pub pconv_threshold: f32,
+
+    #[pyo3(get)]
+    pub mismatch_threshold: i32,
+
+    #[pyo3(get)]
+    pub percent_threshold: f32,
+
+    #[pyo3(get)]
+    pub min_score: Option<i32>,
+
+    #[pyo3(get)]
+    pub expected_length: Option<usize>,
+
+    #[pyo3(get)]
+    pub length_tolerance: f32,
+
+    #[pyo3(get)]
+    pub gap_open: i32,
+
+    #[pyo3(get)]
+    pub gap_extend: i32,
+
+    #[pyo3(get)]
+    pub align_mode: AlignMode,
+
+    #[pyo3(get)]
+    pub lowercase_islands: bool,
+
+    /// per reference in `genomes`, whether each position lies in a
+    /// lowercase-annotated island
+    #[pyo3(get)]
+    pub islands: Vec<Vec<bool>>,
+
+    /// forward and reverse primer trimmed from the read ends before alignment
+    #[pyo3(get)]
+    pub trim_primers: Option<(String, String)>,
+
+    #[pyo3(get)]
+    pub primer_mismatches: usize,
+
+    #[pyo3(get)]
+    pub min_ali_len: i32,
+
+    #[pyo3(get)]
+    pub num_threads: Option<usize>,
+
+    #[pyo3(get)]
+    pub strict_alphabet: bool,
+
+    #[pyo3(get)]
+    pub check_reverse: bool,
+
+    #[pyo3(get)]
+    pub exclude_ambiguous: bool,
+
+    /// active 16x16 scoring matrix in `ATGCSWRYKMBVHDNU` order, row by row
+    #[pyo3(get)]
+    pub scoring_matrix: Vec<i32>,
+
+    /// characters kept when parsing the genome
+    #[pyo3(get)]
+    pub alphabet: String,
+
+    /// (line, column, character) of genome characters outside `alphabet`,
+    /// which were dropped
+    #[pyo3(get)]
+    pub filtered_characters: Vec<(usize, usize, char)>,
 }
 
-fn quma_score(a: u8, b: u8) -> i32 {
-    let a = lookup(a);
-    let b = lookup(b);
+// options controlling how each read is aligned and scored
+#[derive(Clone, Copy)]
+pub struct AlignOptions {
+    pub clip_penalty: Option<i32>,
+    pub iupac_match: bool,
+    pub bisulfite_match: bool,
+    pub bisulfite_scoring: bool,
+    pub gap_open: i32,
+    pub gap_extend: i32,
+    pub align_mode: AlignMode,
+    pub check_reverse: bool,
+}
 
-    MATRIX[(a, b)]
+// exclusion thresholds applied to the best alignment of each read
+#[derive(Clone, Copy)]
+pub struct Thresholds {
+    pub unconv: i32,
+    pub pconv: f32,
+    pub mis: i32,
+    pub perc: f32,
+    pub min_score: Option<i32>,
+    pub expected_length: Option<usize>,
+    pub length_tolerance: f32,
+    pub min_ali_len: i32,
+    pub exclude_ambiguous: bool,
 }
 
-/// Run pairwise sequence alignment
-///
-/// # Arguments
-///
-/// * `gfile` - genomic sequence file contents
-/// * `qfile` - sequencing read(s) file contents
-///
-/// # Returns
-///
-/// * `QumaResult` - alignment result struct
-fn align_seq_and_generate_stats(qfile: &str, gfile: &str) -> QumaResult {
-    let mut this_result = QumaResult {
-        q_ali: "".to_string(),
-        g_ali: "".to_string(),
-        val: "".to_string(),
-        perc: 0.0,
-        pconv: 0.0,
-        gap: 0,
-        menum: 0,
-        unconv: 0,
-        conv: 0,
-        quma_match: 0,
-        ali_mis: 0,
-        ali_len: 0,
-    };
+// chainable configuration of a Quma analysis.
+// fields are the options of the Quma constructor, with the same defaults.
+#[pyclass]
+#[derive(Clone)]
+pub struct QumaBuilder {
+    #[pyo3(get, set)]
+    pub genome_header: bool,
 
-    let bio_gseq = gfile.lines().nth(1).unwrap().as_bytes();
-    let bio_qseq = qfile.lines().nth(1).unwrap().as_bytes();
+    #[pyo3(get, set)]
+    pub clip_penalty: Option<i32>,
 
-    let mut aligner = Aligner::new(-10, -1, &quma_score);
-    // TODO: Custom matrix for CpG
-    // See https://docs.rs/bio/latest/src/bio/scores/blosum62.rs.html#89-94
+    #[pyo3(get, set)]
+    pub unconv_threshold: i32,
+
+    #[pyo3(get, set)]
+    pub pconv_threshold: f32,
 
-    let bio_alignments = aligner.local(bio_gseq, bio_qseq);
+    #[pyo3(get, set)]
+    pub mismatch_threshold: i32,
 
-    let (query_ali, genome_ali) = matching_substrings(&bio_alignments, &bio_gseq, &bio_qseq);
+    #[pyo3(get, set)]
+    pub percent_threshold: f32,
 
-    let fh_ = format!(">genome\n{}\n>que\n{}\n", genome_ali, query_ali);
+    #[pyo3(get, set)]
+    pub iupac_match: bool,
 
-    let fh = fh_.lines();
+    #[pyo3(get, set)]
+    pub fastq: bool,
 
-    for (i, el) in fh.clone().enumerate() {
-        if el.contains(">que") {
-            this_result.q_ali = fh.clone().nth(i + 1).unwrap().to_string();
-        } else if el.contains(">genome") {
-            this_result.g_ali = fh.clone().nth(i + 1).unwrap().to_string();
-        }
+    #[pyo3(get, set)]
+    pub min_score: Option<i32>,
+
+    #[pyo3(get, set)]
+    pub bisulfite_scoring: bool,
+
+    #[pyo3(get, set)]
+    pub expected_length: Option<usize>,
+
+    #[pyo3(get, set)]
+    pub length_tolerance: f32,
+
+    #[pyo3(get, set)]
+    pub gap_open: i32,
+
+    #[pyo3(get, set)]
+    pub gap_extend: i32,
+
+    #[pyo3(get, set)]
+    pub contexts: Vec<Context>,
+
+    #[pyo3(get, set)]
+    pub align_mode: AlignMode,
+
+    #[pyo3(get, set)]
+    pub lowercase_islands: bool,
+
+    #[pyo3(get, set)]
+    pub trim_primers: Option<(String, String)>,
+
+    #[pyo3(get, set)]
+    pub primer_mismatches: usize,
+
+    #[pyo3(get, set)]
+    pub min_ali_len: i32,
+
+    #[pyo3(get, set)]
+    pub num_threads: Option<usize>,
+
+    #[pyo3(get, set)]
+    pub strict_alphabet: bool,
+
+    #[pyo3(get, set)]
+    pub check_reverse: bool,
+
+    #[pyo3(get, set)]
+    pub exclude_ambiguous: bool,
+
+    #[pyo3(get, set)]
+    pub scoring_matrix: Option<Vec<i32>>,
+
+    #[pyo3(get, set)]
+    pub alphabet: Option<String>,
+}
+
+impl Default for QumaBuilder {
+    fn default() -> Self {
+        return QumaBuilder {
+            genome_header: true,
+            clip_penalty: None,
+            unconv_threshold: 5,
+            pconv_threshold: 95.0,
+            mismatch_threshold: 10,
+            percent_threshold: 90.0,
+            iupac_match: true,
+            fastq: false,
+            min_score: None,
+            bisulfite_scoring: false,
+            expected_length: None,
+            length_tolerance: 20.0,
+            gap_open: -10,
+            gap_extend: -1,
+            contexts: vec![Context::CpG],
+            align_mode: AlignMode::Local,
+            lowercase_islands: false,
+            trim_primers: None,
+            primer_mismatches: 2,
+            min_ali_len: 0,
+            num_threads: None,
+            strict_alphabet: false,
+            check_reverse: true,
+            exclude_ambiguous: false,
+            scoring_matrix: None,
+            alphabet: None,
+        };
+    }
+}
+
+#[pymethods]
+impl QumaBuilder {
+    #[new]
+    fn py_new() -> Self {
+        return QumaBuilder::default();
+    }
+
+    /// Set the exclusion thresholds; omitted thresholds are left unchanged
+    ///
+    /// # Arguments
+    ///
+    /// * `unconv` - exclude reads with more unconverted CpGs
+    /// * `pconv` - exclude reads with a higher conversion percentage
+    /// * `mismatch` - exclude reads with more alignment mismatches
+    /// * `percent` - exclude reads with a higher match percentage
+    #[pyo3(signature = (unconv = None, pconv = None, mismatch = None, percent = None))]
+    fn with_thresholds(
+        mut slf: PyRefMut<'_, Self>,
+        unconv: Option<i32>,
+        pconv: Option<f32>,
+        mismatch: Option<i32>,
+        percent: Option<f32>,
+    ) -> PyRefMut<'_, Self> {
+        slf.unconv_threshold = unconv.unwrap_or(slf.unconv_threshold);
+        slf.pconv_threshold = pconv.unwrap_or(slf.pconv_threshold);
+        slf.mismatch_threshold = mismatch.unwrap_or(slf.mismatch_threshold);
+        slf.percent_threshold = percent.unwrap_or(slf.percent_threshold);
+        return slf;
+    }
+
+    /// Set the alignment gap penalties
+    ///
+    /// # Arguments
+    ///
+    /// * `gap_open` - alignment gap-open penalty
+    /// * `gap_extend` - alignment gap-extend penalty
+    fn with_gap_penalties(
+        mut slf: PyRefMut<'_, Self>,
+        gap_open: i32,
+        gap_extend: i32,
+    ) -> PyRefMut<'_, Self> {
+        slf.gap_open = gap_open;
+        slf.gap_extend = gap_extend;
+        return slf;
+    }
+
+    /// Set the alignment mode used when no clip penalty is given
+    ///
+    /// # Arguments
+    ///
+    /// * `align_mode` - local, global or semiglobal alignment
+    fn with_mode(mut slf: PyRefMut<'_, Self>, align_mode: AlignMode) -> PyRefMut<'_, Self> {
+        slf.align_mode = align_mode;
+        return slf;
     }
 
-    this_result.q_ali = this_result.q_ali.replace(" ", "-");
-    this_result.g_ali = this_result.g_ali.replace(" ", "-");
+    /// Set the cytosine contexts to call
+    ///
+    /// # Arguments
+    ///
+    /// * `contexts` - cytosine contexts to call
+    fn with_context(mut slf: PyRefMut<'_, Self>, contexts: Vec<Context>) -> PyRefMut<'_, Self> {
+        slf.contexts = contexts;
+        return slf;
+    }
 
-    let final_result = process_alignment_matches(this_result);
+    /// Set whether genome C against query T scores as a match
+    ///
+    /// # Arguments
+    ///
+    /// * `bisulfite_scoring` - align genome C against query T as a match
+    fn with_bisulfite_scoring(
+        mut slf: PyRefMut<'_, Self>,
+        bisulfite_scoring: bool,
+    ) -> PyRefMut<'_, Self> {
+        slf.bisulfite_scoring = bisulfite_scoring;
+        return slf;
+    }
 
-    return final_result;
+    /// Run the configured analysis
+    ///
+    /// # Arguments
+    ///
+    /// * `gfile_contents` - genome fasta file contents
+    /// * `qfile_contents` - query fasta file contents
+    ///
+    /// # Returns
+    ///
+    /// * `Quma` - Quma struct
+    fn build(&self, gfile_contents: String, qfile_contents: String) -> PyResult<Quma> {
+        return Ok(self.run(gfile_contents, qfile_contents)?);
+    }
 }
 
-/// Helper to implement find method for u8 slices
-///
-/// # Arguments
-///
-/// * `haystack` - byte slice to search
-/// * `needle` - byte slice to search for
-///
-/// # Returns
-///
-/// * `Option<usize>` - index of first match
-fn find_subsequence(haystack: &[u8], needle: &[u8]) -> Option<usize> {
-    haystack
-        .windows(needle.len())
-        .position(|window| window == needle)
+impl QumaBuilder {
+    /// Alignment options of the configured analysis
+    pub fn align_options(&self) -> AlignOptions {
+        return AlignOptions {
+            clip_penalty: self.clip_penalty,
+            iupac_match: self.iupac_match,
+            bisulfite_match: true,
+            bisulfite_scoring: self.bisulfite_scoring,
+            gap_open: self.gap_open,
+            gap_extend: self.gap_extend,
+            align_mode: self.align_mode,
+            check_reverse: self.check_reverse,
+        };
+    }
+
+    /// Exclusion thresholds of the configured analysis
+    pub fn thresholds(&self) -> Thresholds {
+        return Thresholds {
+            unconv: self.unconv_threshold,
+            pconv: self.pconv_threshold,
+            mis: self.mismatch_threshold,
+            perc: self.percent_threshold,
+            min_score: self.min_score,
+            expected_length: self.expected_length,
+            length_tolerance: self.length_tolerance,
+            min_ali_len: self.min_ali_len,
+            exclude_ambiguous: self.exclude_ambiguous,
+        };
+    }
+
+    /// Run the configured analysis
+    ///
+    /// # Arguments
+    ///
+    /// * `gfile_contents` - genome fasta file contents
+    /// * `qfile_contents` - query fasta file contents
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Quma, QumaError>` - Quma struct, or the reason the input was rejected
+    pub fn run(&self, gfile_contents: String, qfile_contents: String) -> Result<Quma, QumaError> {
+        return Quma::build(self, gfile_contents, qfile_contents, None);
+    }
 }
 
-/// Process alignment data to populate results dictionary
-///
-/// # Arguments
-///
-/// * `result` - QumaResult struct
-///
-/// # Returns
-///
-/// * `QumaResult` - QumaResult struct with populated results dictionary
-fn process_alignment_matches(mut result: QumaResult) -> QumaResult {
-    let g_ali = result.g_ali.as_bytes();
-    let q_ali = result.q_ali.as_bytes();
+// incremental analysis of a query file too large to hold in memory.
+// reads are parsed and aligned `chunk_size` at a time and yielded in order.
+#[pyclass]
+pub struct QumaStream {
+    reader: Box<dyn BufRead + Send>,
+    // header of the next fasta record, already read from `reader`
+    next_header: Option<String>,
+    genome_files: Vec<(String, String, String)>,
+    options: QumaBuilder,
+    matrix: Array2<i32>,
+    pool: Option<rayon::ThreadPool>,
+    pending: VecDeque<Reference>,
 
-    result.ali_len = q_ali.len() as i32;
+    /// reads parsed so far
+    #[pyo3(get)]
+    pub reads: usize,
 
-    let mut this_sum = 0;
-    let it = q_ali.iter().zip(g_ali.iter());
-    for (a, b) in it {
-        if a == b {
-            this_sum += 1;
-        } else if *a as char == 'T' && *b as char == 'C' {
-            this_sum += 1;
-        }
+    #[pyo3(get)]
+    pub chunk_size: usize,
+}
+
+#[pymethods]
+impl QumaStream {
+    fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        return slf;
     }
 
-    result.quma_match = this_sum;
+    fn __next__(&mut self) -> PyResult<Option<Reference>> {
+        if self.pending.is_empty() {
+            self.align_chunk()?;
+        }
+        return Ok(self.pending.pop_front());
+    }
+}
 
-    let g_ali_count = g_ali.iter().filter(|&x| x == &b'-').count();
-    let q_ali_count = q_ali.iter().filter(|&x| x == &b'-').count();
+impl QumaStream {
+    /// Parse the next read of the query file
+    ///
+    /// Headers without a sequence and malformed fastq records are skipped.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Option<Fasta>, QumaError>` - next read, or `None` at the end
+    ///   of the file
+    fn next_record(&mut self) -> Result<Option<Fasta>, QumaError> {
+        loop {
+            let lines = self.next_lines()?;
+            if lines.is_empty() {
+                return Ok(None);
+            }
+            let records = match self.options.fastq {
+                true => parse_biseq_fastq(&lines.join("\n")),
+                false => parse_biseq(&lines.join("\n")),
+            };
+            if let Some(mut fa) = records.into_iter().next() {
+                if let Some(primers) = &self.options.trim_primers {
+                    fa = trim_primer_ends(fa, primers, self.options.primer_mismatches);
+                }
+                return Ok(Some(fa));
+            }
+        }
+    }
 
-    result.gap = cmp::max(
-        g_ali_count.try_into().unwrap(),
-        q_ali_count.try_into().unwrap(),
-    );
+    /// Read the lines of the next record of the query file
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Vec<String>, QumaError>` - header and sequence lines of a
+    ///   fasta record or the four lines of a fastq record; empty at the end
+    ///   of the file
+    fn next_lines(&mut self) -> Result<Vec<String>, QumaError> {
+        let mut lines = Vec::<String>::new();
 
-    let mut exit_cond = 0;
-    let mut i = 0;
-    while exit_cond < 100 {
-        let q_ali_len = q_ali.len();
-        let ni = find_subsequence(&q_ali[i..q_ali_len], b"CG");
-        match ni {
-            Some(val) => {
-                let ni_value = val + i;
-                if q_ali[ni_value] as char == 'T' {
-                    result.quma_match += 1;
-                    result.unconv += 1;
-                    result.val += "0";
-                } else if q_ali[ni_value] as char == 'C' {
-                    result.conv += 1;
-                    result.val += "1";
-                    result.menum += 1;
-                } else {
-                    result.val += &q_ali[ni_value].to_string();
+        if self.options.fastq {
+            while lines.len() < 4 {
+                match self.next_line()? {
+                    Some(line) if line.trim().is_empty() => continue,
+                    Some(line) => lines.push(line),
+                    None => break,
                 }
+            }
+            return Ok(lines);
+        }
 
-                i = ni_value + 1;
-                exit_cond += 1;
+        let header = match self.next_header.take() {
+            Some(header) => header,
+            None => loop {
+                match self.next_line()? {
+                    Some(line) if line.starts_with('>') => break line,
+                    Some(_) => continue,
+                    None => return Ok(lines),
+                }
             },
-            None => {
-                exit_cond = 1001;
+        };
+        lines.push(header);
+        while let Some(line) = self.next_line()? {
+            if line.starts_with('>') {
+                self.next_header = Some(line);
+                break;
             }
+            lines.push(line);
         }
+        return Ok(lines);
     }
 
-    if result.val == "" {
-        result.val = "-".to_string();
+    /// Read one line of the query file without its line ending
+    fn next_line(&mut self) -> Result<Option<String>, QumaError> {
+        let mut line = String::new();
+        let read = self
+            .reader
+            .read_line(&mut line)
+            .map_err(|e| QumaError::Decode(e.to_string()))?;
+        if read == 0 {
+            return Ok(None);
+        }
+        return Ok(Some(line.trim_end_matches(&['\r', '\n'][..]).to_string()));
     }
 
-    let results = generate_summary_stats(result);
-    return results;
+    /// Align the next `chunk_size` reads into `pending`
+    fn align_chunk(&mut self) -> Result<(), QumaError> {
+        let mut chunk = Vec::<Fasta>::new();
+        while chunk.len() < self.chunk_size {
+            match self.next_record()? {
+                Some(fa) => chunk.push(fa),
+                None => break,
+            }
+        }
+        if chunk.is_empty() {
+            return Ok(());
+        }
+
+        let first = self.reads;
+        self.reads += chunk.len();
+        let options = self.options.align_options();
+        let thresholds = self.options.thresholds();
+        let contexts = &self.options.contexts;
+        let genome_files = &self.genome_files;
+        let data = process_fasta_output(
+            chunk,
+            String::from("queryF"),
+            String::from("queryR"),
+            genome_files,
+            contexts,
+            options,
+            thresholds,
+            &self.matrix,
+            self.pool.as_ref(),
+            None,
+        )?;
+
+        // positions count from the start of the file, not the chunk
+        for (i, mut reference) in data.into_iter().enumerate() {
+            reference.fasta.pos = (first + i + 1).to_string();
+            self.pending.push_back(reference);
+        }
+        return Ok(());
+    }
 }
 
-/// Helper to generate summary statistics in QumaResult struct
+/// Build a thread pool of a given size
 ///
 /// # Arguments
 ///
-/// * `result` - QumaResult struct
+/// * `num_threads` - number of threads, or `None` to use the global rayon pool
 ///
 /// # Returns
 ///
-/// * `QumaResult` - QumaResult struct with summary statistics
-fn generate_summary_stats(mut result: QumaResult) -> QumaResult {
-    if result.conv + result.unconv != 0 {
-        result.pconv = percentage(result.conv, result.unconv, "sum".to_string())
-    } else {
-        result.pconv = 0.0;
-    }
-
-    result.perc = percentage(result.quma_match, result.ali_len, "total".to_string());
-    result.ali_mis = result.ali_len - result.quma_match;
-    return result;
+/// * `Result<Option<rayon::ThreadPool>, QumaError>` - local pool, if requested
+fn thread_pool(num_threads: Option<usize>) -> Result<Option<rayon::ThreadPool>, QumaError> {
+    return match num_threads {
+        Some(threads) => rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()
+            .map(Some)
+            .map_err(|e| QumaError::ThreadPool(e.to_string())),
+        None => Ok(None),
+    };
 }
 
-/// Helper to return percentages
+/// Open a possibly gzip-compressed file for buffered reading
 ///
 /// # Arguments
 ///
-/// * `a` - numerator
-/// * `b` - denominator
-/// * `calc_type` - type of calculation to perform (`sum` or `total`)
+/// * `path` - path of the file
 ///
 /// # Returns
 ///
-/// * `f32` - percentage
-fn percentage(a: i32, b: i32, calc_type: String) -> f32 {
-    if calc_type == "sum".to_string() {
-        return 100.0 * a as f32 / (a as f32 + b as f32);
-    } else if calc_type == "total".to_string() {
-        return 100.0 * a as f32 / b as f32;
-    } else {
-        return 0.0;
+/// * `std::io::Result<Box<dyn BufRead + Send>>` - reader of the decoded file
+fn open_reader(path: &str) -> std::io::Result<Box<dyn BufRead + Send>> {
+    let mut reader = BufReader::new(fs::File::open(path)?);
+    if reader.fill_buf()?.starts_with(&GZIP_MAGIC) {
+        return Ok(Box::new(BufReader::new(MultiGzDecoder::new(reader))));
     }
-    // TODO: Implement error behavior
+    return Ok(Box::new(reader));
 }
 
-/// Helper to find best data returned
+/// Create new Quma struct
 ///
 /// # Arguments
 ///
-/// * `ffres` - quma result from forward alignment
-/// * `frres` - quma result from reverse alignment
+/// * `gfile_contents` - genome fasta file contents
+/// * `qfile_contents` - query fasta file contents
+/// * `genome_header` - whether to prepend the genome line to `values`
+/// * `clip_penalty` - score for clipping read ends, or `None` for local alignment
+/// * `unconv_threshold` - exclude reads with more unconverted CpGs
+/// * `pconv_threshold` - exclude reads with a higher conversion percentage
+/// * `mismatch_threshold` - exclude reads with more alignment mismatches
+/// * `percent_threshold` - exclude reads with a higher match percentage
+/// * `iupac_match` - count query ambiguity codes compatible with the genome as matches
+/// * `fastq` - whether the query file is fastq rather than fasta
+/// * `min_score` - discard orientations with a lower alignment score
+/// * `bisulfite_scoring` - align genome C against query T as a match
+/// * `expected_length` - expected read length of an amplicon
+/// * `length_tolerance` - exclude reads deviating from `expected_length` by a
+///   higher percentage
+/// * `gap_open` - alignment gap-open penalty
+/// * `gap_extend` - alignment gap-extend penalty
+/// * `contexts` - cytosine contexts to call
+/// * `align_mode` - local, global or semiglobal alignment when `clip_penalty`
+///   is `None`
+/// * `lowercase_islands` - treat lowercase genome bases as CpG-island annotations
+/// * `trim_primers` - forward and reverse primer to strip from the read ends
+///   before alignment
+/// * `primer_mismatches` - mismatches allowed when matching a primer
+/// * `min_ali_len` - exclude reads with a shorter alignment
+/// * `num_threads` - threads used to align reads, or `None` for the global
+///   rayon pool
+/// * `strict_alphabet` - reject a genome with characters outside the allowed
+///   alphabet instead of logging a warning and dropping them
+/// * `check_reverse` - also align the reverse complement of each read;
+///   without it every read is reported as forward
+/// * `exclude_ambiguous` - exclude reads matching several genome references
+///   equally well instead of assigning them to the first reference by name
+/// * `scoring_matrix` - flattened 16x16 substitution scores in
+///   `ATGCSWRYKMBVHDNU` order replacing the default matrix
+/// * `alphabet` - characters kept when parsing the genome, replacing
+///   `ALPHABET`; characters are matched as given, so include lowercase bases
+///   to keep them
+/// * `progress` - callable receiving `(reads_done, total_reads)` as reads are
+///   aligned
+/// * `progress_every` - distinct reads aligned between progress calls
+/// * `read_filter` - callable receiving each non-excluded `Reference` and
+///   returning whether to keep it
 ///
 /// # Returns
 ///
-/// * `(QumaResult, i32)` - tuple of best QumaResult and direction
-fn find_best_dataset(ffres: QumaResult, frres: QumaResult) -> (QumaResult, i32) {
-    // FIXME: Find best dataset better
+/// * `Quma` - Quma struct
+#[pymethods]
+impl Quma {
+    #[new]
+    #[pyo3(signature = (
+        gfile_contents,
+        qfile_contents,
+        genome_header = true,
+        clip_penalty = None,
+        unconv_threshold = 5,
+        pconv_threshold = 95.0,
+        mismatch_threshold = 10,
+        percent_threshold = 90.0,
+        iupac_match = true,
+        fastq = false,
+        min_score = None,
+        bisulfite_scoring = false,
+        expected_length = None,
+        length_tolerance = 20.0,
+        gap_open = -10,
+        gap_extend = -1,
+        contexts = vec![Context::CpG],
+        align_mode = AlignMode::Local,
+        lowercase_islands = false,
+        trim_primers = None,
+        primer_mismatches = 2,
+        min_ali_len = 0,
+        num_threads = None,
+        strict_alphabet = false,
+        check_reverse = true,
+        exclude_ambiguous = false,
+        scoring_matrix = None,
+        alphabet = None,
+        progress = None,
+        progress_every = 100,
+        read_filter = None,
+    ))]
+    #[allow(clippy::too_many_arguments)]
+    fn py_new(
+        py: Python<'_>,
+        gfile_contents: String,
+        qfile_contents: String,
+        genome_header: bool,
+        clip_penalty: Option<i32>,
+        unconv_threshold: i32,
+        pconv_threshold: f32,
+        mismatch_threshold: i32,
+        percent_threshold: f32,
+        iupac_match: bool,
+        fastq: bool,
+        min_score: Option<i32>,
+        bisulfite_scoring: bool,
+        expected_length: Option<usize>,
+        length_tolerance: f32,
+        gap_open: i32,
+        gap_extend: i32,
+        contexts: Vec<Context>,
+        align_mode: AlignMode,
+        lowercase_islands: bool,
+        trim_primers: Option<(String, String)>,
+        primer_mismatches: usize,
+        min_ali_len: i32,
+        num_threads: Option<usize>,
+        strict_alphabet: bool,
+        check_reverse: bool,
+        exclude_ambiguous: bool,
+        scoring_matrix: Option<Vec<i32>>,
+        alphabet: Option<String>,
+        progress: Option<PyObject>,
+        progress_every: usize,
+        read_filter: Option<PyObject>,
+    ) -> PyResult<Self> {
+        // the callback runs on this thread between chunks of aligned reads
+        let mut progress_err: Option<PyErr> = None;
+        let mut callback = |done: usize, total: usize| {
+            if let (Some(progress), None) = (&progress, &progress_err) {
+                if let Err(err) = progress.call1(py, (done, total)) {
+                    progress_err = Some(err);
+                }
+            }
+        };
+        let mut report: Option<Progress> = None;
+        if progress.is_some() {
+            let callback: &mut dyn FnMut(usize, usize) = &mut callback;
+            report = Some((progress_every, callback));
+        }
 
-    if ffres.ali_len > frres.ali_len {
-        let fres = ffres;
-        let fdir = 1;
-        return (fres.clone(), fdir);
-    } else {
-        let fres = frres;
-        let fdir = -1;
-        return (fres.clone(), fdir);
+        let options = QumaBuilder {
+            genome_header,
+            clip_penalty,
+            unconv_threshold,
+            pconv_threshold,
+            mismatch_threshold,
+            percent_threshold,
+            iupac_match,
+            fastq,
+            min_score,
+            bisulfite_scoring,
+            expected_length,
+            length_tolerance,
+            gap_open,
+            gap_extend,
+            contexts,
+            align_mode,
+            lowercase_islands,
+            trim_primers,
+            primer_mismatches,
+            min_ali_len,
+            num_threads,
+            strict_alphabet,
+            check_reverse,
+            exclude_ambiguous,
+            scoring_matrix,
+            alphabet,
+        };
+        let mut quma = Quma::build(&options, gfile_contents, qfile_contents, report)?;
+        if let Some(err) = progress_err {
+            return Err(err);
+        }
+
+        if let Some(read_filter) = read_filter {
+            for reference in quma.data.iter_mut().filter(|x| x.exc == 0) {
+                let keep: bool = read_filter.call1(py, (reference.clone(),))?.extract(py)?;
+                if !keep {
+                    reference.exc = 1;
+                    reference.reason = Some("filter".to_string());
+                }
+            }
+        }
+
+        return Ok(quma);
     }
-}
 
-/// Process program output into quma-formatted string
-///
-/// # Arguments
-///
-/// * `gseq` - genomic sequence
-/// * `data` - vector of Reference structs
-///
-/// # Returns
-///
-/// * `String` - tabular quma-formatted string
-fn format_output(gseq: &str, data: &Vec<Reference>) -> String {
-    let header_output = format!("genome\t0\t{}\t1\t0\n", gseq);
+    /// Create new Quma struct from genome and query file paths
+    ///
+    /// # Arguments
+    ///
+    /// * `gpath` - path of the genome fasta file, plain or gzip-compressed
+    /// * `qpath` - path of the query file, plain or gzip-compressed
+    /// * `options` - analysis options, or `None` for the defaults
+    ///
+    /// # Returns
+    ///
+    /// * `Quma` - Quma struct
+    #[staticmethod]
+    #[pyo3(signature = (gpath, qpath, options = None))]
+    fn from_files(gpath: String, qpath: String, options: Option<QumaBuilder>) -> PyResult<Self> {
+        let gfile_bytes =
+            fs::read(&gpath).map_err(|e| PyIOError::new_err(format!("{}: {}", gpath, e)))?;
+        let qfile_bytes =
+            fs::read(&qpath).map_err(|e| PyIOError::new_err(format!("{}: {}", qpath, e)))?;
+        return Quma::from_gzip(&gfile_bytes, &qfile_bytes, options);
+    }
 
-    let mut output_holder: Vec<String> = Vec::new();
-    for reference in data {
-        output_holder.push(format!("{}\t", reference.fasta.pos));
-        output_holder.push(format!("{}\t", reference.fasta.com));
-        output_holder.push(format!("{}\t", reference.fasta.seq));
-        output_holder.push(format!("{}\t", reference.res.q_ali));
-        output_holder.push(format!("{}\t", reference.res.g_ali));
-        output_holder.push(format!("{}\t", reference.res.ali_len));
-        output_holder.push(format!("{}\t", reference.res.ali_mis));
-        output_holder.push(format!("{}\t", reference.res.perc));
-        output_holder.push(format!("{}\t", reference.res.gap));
-        output_holder.push(format!("{}\t", reference.res.menum));
-        output_holder.push(format!("{}\t", reference.res.unconv));
-        output_holder.push(format!("{}\t", reference.res.conv));
-        output_holder.push(format!("{}\t", reference.res.pconv));
-        output_holder.push(format!("{}\t", reference.res.val));
-        output_holder.push(format!("{}\t", reference.dir));
-        output_holder.push(format!("{}\t", reference.gdir));
-        output_holder.push(format!("\n"));
-    }
-    let joined = output_holder.join("");
-
-    return format!("{}{}", header_output, joined);
-}
+    /// Stream the analysis of a large query file, a chunk of reads at a time
+    ///
+    /// Only the current chunk of reads is held in memory; references are
+    /// yielded in file order as they are aligned.
+    ///
+    /// # Arguments
+    ///
+    /// * `gfile_contents` - genome fasta file contents
+    /// * `qpath` - path of the query file, plain or gzip-compressed
+    /// * `options` - analysis options, or `None` for the defaults
+    /// * `chunk_size` - reads aligned together
+    ///
+    /// # Returns
+    ///
+    /// * `QumaStream` - iterator of Reference structs
+    #[staticmethod]
+    #[pyo3(signature = (gfile_contents, qpath, options = None, chunk_size = 1000))]
+    fn stream(
+        gfile_contents: &str,
+        qpath: String,
+        options: Option<QumaBuilder>,
+        chunk_size: usize,
+    ) -> PyResult<QumaStream> {
+        let options = options.unwrap_or_default();
+        let alphabet = allowed_alphabet(options.alphabet.clone())?;
+        let disallowed = disallowed_characters(gfile_contents, &alphabet);
+        if !disallowed.is_empty() {
+            let err = QumaError::DisallowedCharacters(disallowed);
+            if options.strict_alphabet {
+                return Err(err.into());
+            }
+            warn!("{}; they are ignored", err);
+        }
+        let genomes = parse_genomes_in(gfile_contents, &alphabet);
+        if genomes.is_empty() {
+            return Err(QumaError::EmptyGenome.into());
+        }
+        let reader =
+            open_reader(&qpath).map_err(|e| PyIOError::new_err(format!("{}: {}", qpath, e)))?;
+        let pool = thread_pool(options.num_threads)?;
+        let matrix = scoring_matrix(options.scoring_matrix.clone())?;
 
-// Run quma and return the quma object
-#[pyfunction]
-fn quma(gseq: String, qseq: String) -> PyResult<Quma> {
-    Ok::<Quma, PyErr>(Quma::py_new(gseq.clone(), qseq.clone()))
-}
+        return Ok(QumaStream {
+            reader,
+            next_header: None,
+            genome_files: genome_fastas(&genomes),
+            options,
+            matrix,
+            pool,
+            pending: VecDeque::new(),
+            reads: 0,
+            chunk_size: cmp::max(chunk_size, 1),
+        });
+    }
 
-/// A Python module implemented in Rust.
-#[pymodule]
+    /// Create new Quma struct from possibly gzip-compressed file contents
+    ///
+    /// # Arguments
+    ///
+    /// * `gfile_bytes` - genome file contents, plain or gzip-compressed
+    /// * `qfile_bytes` - query file contents, plain or gzip-compressed
+    /// * `options` - analysis options, or `None` for the defaults
+    ///
+    /// # Returns
+    ///
+    /// * `Quma` - Quma struct
+    #[staticmethod]
+    #[pyo3(signature = (gfile_bytes, qfile_bytes, options = None))]
+    fn from_gzip(
+        gfile_bytes: &[u8],
+        qfile_bytes: &[u8],
+        options: Option<QumaBuilder>,
+    ) -> PyResult<Self> {
+        let gfile_contents = decode_input(gfile_bytes)?;
+        let qfile_contents = decode_input(qfile_bytes)?;
+        let options = options.unwrap_or_default();
+        return Ok(options.run(gfile_contents, qfile_contents)?);
+    }
+
+    /// Re-call methylation in other cytosine contexts without re-aligning
+    ///
+    /// Reads are re-checked against the stored exclusion thresholds, since
+    /// `unconv` and `pconv` follow the calls. Exclusions that do not depend on
+    /// the calls (unaligned, length, ambiguous reference) are kept, as is a
+    /// `read_filter` exclusion of a read that still passes the thresholds.
+    ///
+    /// # Arguments
+    ///
+    /// * `contexts` - cytosine contexts to call
+    fn recall_methylation(&mut self, contexts: Vec<Context>) {
+        let thresholds = self.thresholds();
+        for reference in self.data.iter_mut() {
+            let mut res = call_methylation(reference.res.clone(), &contexts);
+            let glen = self
+                .genomes
+                .iter()
+                .find(|(name, _)| name == &reference.genome)
+                .map_or(0, |(_, seq)| seq.len());
+            res = forward_positions(res, reference.gdir, glen);
+            reference.res = generate_summary_stats(res);
+
+            let reason = match reference.reason.as_deref() {
+                Some(reason @ ("unaligned" | "length" | "ambiguous")) => Some(reason),
+                Some("filter") => {
+                    alignment_exclusion(&reference.res, &thresholds).or(Some("filter"))
+                }
+                _ => alignment_exclusion(&reference.res, &thresholds),
+            };
+            reference.exc = reason.is_some() as i32;
+            reference.reason = reason.map(|x| x.to_string());
+        }
+
+        self.values = format_output(&self.genomes, &self.data, self.genome_header);
+        self.contexts = contexts;
+    }
+
+    /// Re-count matches of the stored alignments under new match rules
+    ///
+    /// Updates `quma_match`, `perc` and `ali_mis` of each read without
+    /// re-aligning; methylation calls are left unchanged.
+    ///
+    /// # Arguments
+    ///
+    /// * `bisulfite_match` - count query T against genome C as a match
+    /// * `iupac_match` - count query ambiguity codes compatible with the genome as matches
+    #[pyo3(signature = (bisulfite_match = true, iupac_match = true))]
+    fn rescore_matches(&mut self, bisulfite_match: bool, iupac_match: bool) {
+        for reference in self.data.iter_mut() {
+            reference.res = rescore_matches(reference.res.clone(), bisulfite_match, iupac_match);
+        }
+
+        self.values = format_output(&self.genomes, &self.data, self.genome_header);
+        self.bisulfite_match = bisulfite_match;
+        self.iupac_match = iupac_match;
+    }
+
+    /// Count the cytosine contexts of a reference sequence
+    ///
+    /// # Arguments
+    ///
+    /// * `genome` - reference name, or `None` for the first reference
+    ///
+    /// # Returns
+    ///
+    /// * `HashMap<String, i32>` - count of CpG, CHG and CHH sites
+    #[pyo3(signature = (genome = None))]
+    fn genome_context_counts(&self, genome: Option<&str>) -> PyResult<HashMap<String, i32>> {
+        let (_, seq) = self.reference(genome)?;
+        return Ok(context_counts(seq));
+    }
+
+    /// Compute methylation pooled over the CpG calls of non-excluded reads
+    ///
+    /// # Arguments
+    ///
+    /// * `identity_weighted` - weight each read by its identity (`perc` / 100)
+    /// * `balance_strands` - downsample the overrepresented read orientation to
+    ///   the size of the other, keeping its longest alignments
+    ///
+    /// # Returns
+    ///
+    /// * `f32` - pooled methylation percentage
+    #[pyo3(signature = (identity_weighted = false, balance_strands = false))]
+    fn weighted_methylation(&self, identity_weighted: bool, balance_strands: bool) -> f32 {
+        if balance_strands {
+            return weighted_methylation(&balanced_reads(&self.data), identity_weighted);
+        }
+        return weighted_methylation(&self.data, identity_weighted);
+    }
+
+    /// Find reads whose methylation contradicts their conversion
+    ///
+    /// # Returns
+    ///
+    /// * `Vec<usize>` - indices into `data` of reads with suspect methylation
+    fn suspect_reads(&self) -> Vec<usize> {
+        return (0..self.data.len())
+            .filter(|&i| self.data[i].res.conversion_suspect())
+            .collect();
+    }
+
+    /// Compute methylation by read strand and flag significant strand bias
+    ///
+    /// # Returns
+    ///
+    /// * `StrandBias` - per-strand methylation and strand bias flag
+    fn strand_bias(&self) -> StrandBias {
+        return strand_bias(&self.data);
+    }
+
+    /// Aggregate read counts and methylation across all reads
+    ///
+    /// # Returns
+    ///
+    /// * `Summary` - read counts and methylation of non-excluded reads
+    fn summary(&self) -> Summary {
+        return summary(&self.data);
+    }
+
+    /// Reads that passed the exclusion criteria, in input order
+    ///
+    /// # Returns
+    ///
+    /// * `Vec<Reference>` - Reference structs with `exc == 0`
+    fn included(&self) -> Vec<Reference> {
+        return self.data.iter().filter(|x| x.exc == 0).cloned().collect();
+    }
+
+    /// Reads that failed the exclusion criteria, in input order
+    ///
+    /// # Returns
+    ///
+    /// * `Vec<Reference>` - Reference structs with `exc == 1`
+    fn excluded(&self) -> Vec<Reference> {
+        return self.data.iter().filter(|x| x.exc != 0).cloned().collect();
+    }
+
+    /// Count distinct molecules among non-excluded reads
+    ///
+    /// Reads are compared by their analyzed sequence in the forward read
+    /// orientation, so a read and its reverse complement are one molecule.
+    ///
+    /// # Returns
+    ///
+    /// * `i32` - number of distinct read sequences
+    fn unique_count(&self) -> i32 {
+        return unique_count(&self.data);
+    }
+
+    /// Percentage of non-excluded reads that repeat an earlier molecule
+    ///
+    /// # Returns
+    ///
+    /// * `f32` - duplicate reads as a percentage of non-excluded reads, 0
+    ///   without reads
+    fn duplication_rate(&self) -> f32 {
+        let included = self.data.iter().filter(|x| x.exc == 0).count() as i32;
+        let duplicates = included - unique_count(&self.data);
+        return percentage(duplicates, included, PercentType::Total).unwrap_or(0.0);
+    }
+
+    /// Count excluded reads by exclusion reason
+    ///
+    /// # Returns
+    ///
+    /// * `HashMap<String, i32>` - number of excluded reads per reason
+    fn exclusion_summary(&self) -> HashMap<String, i32> {
+        let mut summary = HashMap::<String, i32>::new();
+        for reference in self.data.iter().filter(|x| x.exc == 1) {
+            let reason = reference.reason.clone().unwrap_or_default();
+            *summary.entry(reason).or_insert(0) += 1;
+        }
+        return summary;
+    }
+
+    /// Estimate bisulfite conversion efficiency from non-CpG cytosines
+    ///
+    /// # Returns
+    ///
+    /// * `(f32, f32, f32)` - conversion percentage with the lower and upper
+    ///   bounds of its 95% Wilson confidence interval
+    fn conversion_efficiency(&self) -> (f32, f32, f32) {
+        return conversion_efficiency(&self.data);
+    }
+
+    /// Methylation of non-excluded reads, pooled and as a mean of reads
+    ///
+    /// The pooled level weights each read by its number of called sites; the
+    /// mean weights each read equally, so reads with few sites count as much
+    /// as reads with many.
+    ///
+    /// # Returns
+    ///
+    /// * `(f32, f32)` - pooled and mean per-read methylation percentage
+    fn methylation_levels(&self) -> (f32, f32) {
+        return methylation_levels(&self.data);
+    }
+
+    /// Pool methylation of non-excluded reads at each called genome coordinate
+    ///
+    /// Reads are merged by the `cpg_positions` of their calls, so reads
+    /// aligned to shifted windows of the genome pool at their shared sites.
+    /// Only reads assigned to `genome` are pooled.
+    ///
+    /// # Arguments
+    ///
+    /// * `genome` - reference name, or `None` for the first reference
+    ///
+    /// # Returns
+    ///
+    /// * `BTreeMap<usize, (i32, i32)>` - methylated and covering read counts per site
+    #[pyo3(signature = (genome = None))]
+    fn site_methylation(&self, genome: Option<&str>) -> PyResult<BTreeMap<usize, (i32, i32)>> {
+        let (name, _) = self.reference(genome)?;
+        return Ok(site_methylation(&self.data, name));
+    }
+
+    /// Consensus methylation of non-excluded reads at each called position
+    ///
+    /// Calls are pooled by the `cpg_positions` of each read; reads not
+    /// covering a position do not count towards it.
+    ///
+    /// # Arguments
+    ///
+    /// * `genome` - reference name, or `None` for the first reference
+    ///
+    /// # Returns
+    ///
+    /// * `BTreeMap<usize, (i32, i32, f32)>` - methylated count, covering read
+    ///   count and methylated fraction per genome coordinate
+    #[pyo3(signature = (genome = None))]
+    fn consensus_methylation(
+        &self,
+        genome: Option<&str>,
+    ) -> PyResult<BTreeMap<usize, (i32, i32, f32)>> {
+        let (name, _) = self.reference(genome)?;
+        return Ok(site_methylation(&self.data, name)
+            .into_iter()
+            .map(|(pos, (methylated, covered))| {
+                (
+                    pos,
+                    (methylated, covered, methylated as f32 / covered as f32),
+                )
+            })
+            .collect());
+    }
+
+    /// Fraction of reads disagreeing with the majority call at each site
+    ///
+    /// # Arguments
+    ///
+    /// * `genome` - reference name, or `None` for the first reference
+    ///
+    /// # Returns
+    ///
+    /// * `BTreeMap<usize, f32>` - minority-call fraction per covered site of
+    ///   `site_methylation`
+    #[pyo3(signature = (genome = None))]
+    fn site_discordance(&self, genome: Option<&str>) -> PyResult<BTreeMap<usize, f32>> {
+        let (name, _) = self.reference(genome)?;
+        return Ok(site_methylation(&self.data, name)
+            .into_iter()
+            .map(|(pos, (methylated, covered))| {
+                let minority = cmp::min(methylated, covered - methylated);
+                (pos, minority as f32 / covered as f32)
+            })
+            .collect());
+    }
+
+    /// Epiallele frequency table of non-excluded reads
+    ///
+    /// # Returns
+    ///
+    /// * `String` - tab-separated `pattern count frequency` rows, most
+    ///   frequent methylation pattern first
+    fn epialleles_tsv(&self) -> String {
+        let patterns = epialleles(&self.data);
+        let total: i32 = patterns.iter().map(|(_, count)| count).sum();
+
+        let mut table = String::from("pattern\tcount\tfrequency\n");
+        for (pattern, count) in patterns {
+            let frequency = count as f32 / total as f32;
+            table.push_str(&format!("{}\t{}\t{}\n", pattern, count, frequency));
+        }
+
+        return table;
+    }
+
+    /// Per-read results as a delimited table with a header row
+    ///
+    /// # Arguments
+    ///
+    /// * `csv` - separate columns with commas instead of tabs
+    ///
+    /// # Returns
+    ///
+    /// * `String` - one row per read with the columns of `values` and the
+    ///   genome coordinate of each methylation call
+    #[pyo3(signature = (csv = false))]
+    fn table(&self, csv: bool) -> String {
+        return format_table(&self.data, csv);
+    }
+
+    /// Long table of CpG methylation calls with per-call base quality
+    ///
+    /// # Returns
+    ///
+    /// * `String` - tab-separated `pos read genome site call qual` rows of
+    ///   non-excluded reads
+    fn long_table(&self) -> String {
+        let mut table = String::from("pos\tread\tgenome\tsite\tcall\tqual\n");
+        for (name, _) in &self.genomes {
+            table.push_str(&long_table(&self.data, name, &self.contexts));
+        }
+        return table;
+    }
+
+    /// bedGraph track of the methylation level at each covered CpG
+    ///
+    /// Calls in other contexts enabled by `contexts` are left out.
+    ///
+    /// # Arguments
+    ///
+    /// * `chrom` - chromosome name for the first column
+    /// * `offset` - genomic coordinate of the first genome base
+    /// * `genome` - reference name, or `None` for the first reference
+    ///
+    /// # Returns
+    ///
+    /// * `String` - one `chrom start end level` line per covered CpG
+    #[pyo3(signature = (chrom, offset = 0, genome = None))]
+    fn to_bedgraph(&self, chrom: &str, offset: usize, genome: Option<&str>) -> PyResult<String> {
+        let (name, seq) = self.reference(genome)?;
+        let cpgs = cpg_sites(seq);
+        let mut sites = site_methylation(&self.data, name);
+        sites.retain(|pos, _| cpgs.binary_search(pos).is_ok());
+        return Ok(format_bedgraph(&sites, chrom, offset));
+    }
+
+    /// SAM export of the alignment of every read
+    ///
+    /// Excluded reads are flagged as failing quality checks; primer-trimmed
+    /// bases are hard-clipped.
+    ///
+    /// # Returns
+    ///
+    /// * `String` - SAM header with each genome reference, then one record
+    ///   per read
+    fn to_sam(&self) -> String {
+        let mut sam = String::from("@HD\tVN:1.6\tSO:unsorted\n");
+        for (name, seq) in &self.genomes {
+            sam.push_str(&format!("@SQ\tSN:{}\tLN:{}\n", name, seq.len()));
+        }
+
+        for reference in &self.data {
+            let glen = self.genome_length(&reference.genome);
+            sam.push_str(&sam_record(reference, glen));
+            sam.push('\n');
+        }
+
+        return sam;
+    }
+
+    /// Binary methylation matrix of non-excluded reads by genome CpGs
+    ///
+    /// # Arguments
+    ///
+    /// * `genome` - reference name, or `None` for the first reference
+    ///
+    /// # Returns
+    ///
+    /// * `(Vec<Vec<u8>>, Vec<Vec<u8>>)` - methylation calls and coverage mask
+    #[pyo3(signature = (genome = None))]
+    fn methylation_matrix(&self, genome: Option<&str>) -> PyResult<BinaryRows> {
+        let (calls, mask) = self.binary_matrix(genome)?;
+        return Ok((
+            calls.outer_iter().map(|row| row.to_vec()).collect(),
+            mask.outer_iter().map(|row| row.to_vec()).collect(),
+        ));
+    }
+
+    /// Reads-by-CpG methylation matrix of non-excluded reads for heatmaps
+    ///
+    /// # Arguments
+    ///
+    /// * `genome` - reference name, or `None` for the first reference
+    ///
+    /// # Returns
+    ///
+    /// * `(Vec<Vec<i32>>, Vec<usize>)` - per read, 1 (methylated), 0
+    ///   (unmethylated) or -1 (not covered) at each genome CpG, and the genome
+    ///   coordinate of each column
+    #[pyo3(signature = (genome = None))]
+    fn methylation_heatmap(&self, genome: Option<&str>) -> PyResult<(Vec<Vec<i32>>, Vec<usize>)> {
+        let (_, seq) = self.reference(genome)?;
+        let (calls, mask) = self.binary_matrix(genome)?;
+        let rows = calls
+            .outer_iter()
+            .zip(mask.outer_iter())
+            .map(|(call_row, mask_row)| {
+                call_row
+                    .iter()
+                    .zip(mask_row.iter())
+                    .map(|(&call, &covered)| match covered {
+                        1 => call as i32,
+                        _ => HEATMAP_NOT_COVERED,
+                    })
+                    .collect()
+            })
+            .collect();
+        return Ok((rows, cpg_sites(seq)));
+    }
+
+    /// Pairwise methylation distance between non-excluded reads
+    ///
+    /// # Arguments
+    ///
+    /// * `genome` - reference name, or `None` for the first reference
+    ///
+    /// # Returns
+    ///
+    /// * `Vec<Vec<f32>>` - symmetric matrix of distances between reads
+    #[pyo3(signature = (genome = None))]
+    fn methylation_distance_matrix(&self, genome: Option<&str>) -> PyResult<Vec<Vec<f32>>> {
+        let distances = self.distance_matrix(genome)?;
+        return Ok(distances.outer_iter().map(|row| row.to_vec()).collect());
+    }
+
+    /// Methylation of each genome CpG along an externally supplied read order
+    ///
+    /// # Arguments
+    ///
+    /// * `order` - indices into `data` giving the order of reads
+    /// * `genome` - reference name, or `None` for the first reference
+    ///
+    /// # Returns
+    ///
+    /// * `Vec<Vec<Option<u8>>>` - for each read in `order`, 1 (methylated),
+    ///   0 (unmethylated) or `None` (not covered) at each genome CpG; reads
+    ///   assigned to another reference cover none
+    #[pyo3(signature = (order, genome = None))]
+    fn methylation_trajectory(
+        &self,
+        order: Vec<usize>,
+        genome: Option<&str>,
+    ) -> PyResult<Vec<Vec<Option<u8>>>> {
+        let (name, seq) = self.reference(genome)?;
+        let sites = cpg_sites(seq);
+
+        let mut trajectory = Vec::<Vec<Option<u8>>>::new();
+        for index in order {
+            let reference = match self.data.get(index) {
+                Some(reference) => reference,
+                None => {
+                    return Err(PyIndexError::new_err(format!(
+                        "read index {} out of range",
+                        index
+                    )))
+                }
+            };
+            let mut calls = BTreeMap::<usize, bool>::new();
+            if reference.genome == name {
+                calls = methylation_calls(reference, &self.contexts);
+            }
+            trajectory.push(
+                sites
+                    .iter()
+                    .map(|pos| calls.get(pos).map(|&x| x as u8))
+                    .collect(),
+            );
+        }
+
+        return Ok(trajectory);
+    }
+
+    /// Methylation calls of each read at user-supplied CpG coordinates
+    ///
+    /// # Arguments
+    ///
+    /// * `coordinates` - genome coordinates of CpG cytosines
+    /// * `genome` - reference name, or `None` for the first reference
+    ///
+    /// # Returns
+    ///
+    /// * `Vec<String>` - per read, one character per coordinate: `1`
+    ///   methylated, `0` unmethylated, `m` or `u` at an ambiguous `YG` site,
+    ///   `.` not covered, or the aligned base; reads assigned to another
+    ///   reference cover none
+    #[pyo3(signature = (coordinates, genome = None))]
+    fn calls_at(&self, coordinates: Vec<usize>, genome: Option<&str>) -> PyResult<Vec<String>> {
+        let (name, _) = self.reference(genome)?;
+        return Ok(self
+            .data
+            .iter()
+            .map(|reference| {
+                let mut calls = HashMap::<usize, char>::new();
+                if reference.genome == name {
+                    calls = cpg_calls(reference, &self.contexts).into_iter().collect();
+                }
+                coordinates
+                    .iter()
+                    .map(|pos| calls.get(pos).copied().unwrap_or(NOT_COVERED))
+                    .collect()
+            })
+            .collect());
+    }
+
+    /// CpG-island membership of each call in `val`
+    ///
+    /// Requires `lowercase_islands`; otherwise no call is in an island.
+    ///
+    /// # Returns
+    ///
+    /// * `Vec<Vec<bool>>` - per read, parallel to the characters of its `val`
+    ///   string, whether the call lies in a lowercase-annotated island of its
+    ///   assigned reference
+    fn island_calls(&self) -> Vec<Vec<bool>> {
+        return self
+            .data
+            .iter()
+            .map(|reference| {
+                let mask = self
+                    .genomes
+                    .iter()
+                    .position(|(name, _)| name == &reference.genome)
+                    .and_then(|index| self.islands.get(index))
+                    .map_or(&[][..], |mask| mask.as_slice());
+                reference
+                    .res
+                    .cpg_positions
+                    .iter()
+                    .map(|&pos| mask.get(pos as usize).copied().unwrap_or(false))
+                    .collect()
+            })
+            .collect();
+    }
+
+    /// CpG sites of a reference sequence, independent of any reads
+    ///
+    /// # Arguments
+    ///
+    /// * `genome` - reference name, or `None` for the first reference
+    ///
+    /// # Returns
+    ///
+    /// * `(usize, Vec<usize>)` - number of CpGs and the 0-based genome
+    ///   coordinate of each CpG cytosine
+    #[pyo3(signature = (genome = None))]
+    fn genome_cpg_sites(&self, genome: Option<&str>) -> PyResult<(usize, Vec<usize>)> {
+        let (_, seq) = self.reference(genome)?;
+        let sites = cpg_sites(seq);
+        return Ok((sites.len(), sites));
+    }
+
+    /// Index among all genome CpGs of each call in `val`
+    ///
+    /// # Returns
+    ///
+    /// * `Vec<Vec<Option<usize>>>` - per read, parallel to the characters of
+    ///   its `val` string, the 0-based CpG index on its assigned reference, or
+    ///   `None` for a call in another context
+    fn cpg_indices(&self) -> Vec<Vec<Option<usize>>> {
+        return self
+            .data
+            .iter()
+            .map(|reference| {
+                let sites = cpg_sites(self.genome_seq(&reference.genome));
+                reference
+                    .res
+                    .cpg_positions
+                    .iter()
+                    .map(|&pos| sites.binary_search(&(pos as usize)).ok())
+                    .collect()
+            })
+            .collect();
+    }
+
+    /// Genomic distances between consecutive covered CpGs of each read
+    ///
+    /// # Returns
+    ///
+    /// * `Vec<Vec<usize>>` - per read, the spacing between each pair of
+    ///   neighbouring CpGs with a methylation call
+    fn cpg_spacing(&self) -> Vec<Vec<usize>> {
+        return self
+            .data
+            .iter()
+            .map(|reference| cpg_spacing(reference, &self.contexts))
+            .collect();
+    }
+
+    /// JSON export of the genome sequence and per-read results
+    ///
+    /// # Returns
+    ///
+    /// * `String` - JSON object with `gseq` and `data` keys
+    #[getter]
+    fn get_json(&self) -> PyResult<String> {
+        let export = JsonExport {
+            gseq: &self.gseq,
+            data: &self.data,
+        };
+        return serde_json::to_string(&export).map_err(|e| PyValueError::new_err(e.to_string()));
+    }
+
+    /// Serialized state of the analysis, used for pickling
+    ///
+    /// # Returns
+    ///
+    /// * `String` - JSON of the inputs, options and results
+    fn __getstate__(&self) -> PyResult<String> {
+        return serde_json::to_string(self).map_err(|e| PyValueError::new_err(e.to_string()));
+    }
+
+    /// Restore an analysis from `__getstate__` without re-aligning
+    ///
+    /// # Arguments
+    ///
+    /// * `state` - JSON returned by `__getstate__`
+    ///
+    /// # Returns
+    ///
+    /// * `Quma` - Quma struct
+    #[staticmethod]
+    fn from_state(state: &str) -> PyResult<Self> {
+        return serde_json::from_str(state).map_err(|e| PyValueError::new_err(e.to_string()));
+    }
+
+    fn __reduce__(&self, py: Python<'_>) -> PyResult<(PyObject, (String,))> {
+        let from_state = py.get_type::<Quma>().getattr("from_state")?;
+        return Ok((from_state.into(), (self.__getstate__()?,)));
+    }
+
+    fn __repr__(&self) -> String {
+        let included = self.data.iter().filter(|x| x.exc == 0).count();
+        return format!(
+            "Quma(reads={}, included={}, genome_length={})",
+            self.data.len(),
+            included,
+            self.gseq.len()
+        );
+    }
+
+    // #[getter]
+    // fn get_values(&self) -> PyResult<String> {
+    //     Ok(self.values.clone())
+    // }
+
+    // #[getter]
+    // fn get_data(&self) -> PyResult<Vec<Reference>> {
+    //     Ok(self.data.clone())
+    // }
+}
+
+// heatmap entry of a CpG without a call in the read
+static HEATMAP_NOT_COVERED: i32 = -1;
+
+// borrowed view of the results serialized by `Quma.json`
+#[derive(Serialize)]
+struct JsonExport<'a> {
+    gseq: &'a str,
+    data: &'a [Reference],
+}
+
+// call symbol for a CpG that a read does not cover
+static NOT_COVERED: char = '.';
+
+impl Quma {
+    /// Run the analysis of a genome and its bisulfite reads with default options
+    ///
+    /// # Arguments
+    ///
+    /// * `gfile_contents` - genome fasta file contents
+    /// * `qfile_contents` - query fasta file contents
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Quma, QumaError>` - Quma struct, or the reason the input was rejected
+    pub fn analyze(gfile_contents: &str, qfile_contents: &str) -> Result<Quma, QumaError> {
+        return QumaBuilder::default().run(gfile_contents.to_string(), qfile_contents.to_string());
+    }
+
+    /// Run the analysis
+    ///
+    /// # Arguments
+    ///
+    /// * `options` - analysis options
+    /// * `gfile_contents` - genome fasta file contents
+    /// * `qfile_contents` - query fasta file contents
+    /// * `progress` - number of distinct reads per progress report and the
+    ///   callback receiving `(reads_done, total_reads)`
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Quma, QumaError>` - Quma struct, or the reason the input was rejected
+    fn build(
+        options: &QumaBuilder,
+        gfile_contents: String,
+        qfile_contents: String,
+        progress: Option<Progress>,
+    ) -> Result<Quma, QumaError> {
+        let align_options = options.align_options();
+        let thresholds = options.thresholds();
+
+        let alphabet = allowed_alphabet(options.alphabet.clone())?;
+        let filtered_characters = disallowed_characters(&gfile_contents, &alphabet);
+        if !filtered_characters.is_empty() {
+            let err = QumaError::DisallowedCharacters(filtered_characters.clone());
+            if options.strict_alphabet {
+                return Err(err);
+            }
+            warn!("{}; they are ignored", err);
+        }
+
+        let genomes = parse_genomes_in(&gfile_contents, &alphabet);
+        if genomes.is_empty() {
+            return Err(QumaError::EmptyGenome);
+        }
+
+        // genome-coordinate accessors describe the first reference
+        let gseq = genomes[0].1.clone();
+        let mut islands = Vec::<Vec<bool>>::new();
+        if options.lowercase_islands {
+            islands = island_mask(&gfile_contents, &alphabet);
+        }
+
+        let mut qseq = match options.fastq {
+            true => parse_biseq_fastq(&qfile_contents),
+            false => parse_biseq(&qfile_contents),
+        };
+        if qseq.is_empty() {
+            return Err(QumaError::EmptyQuery);
+        }
+        if let Some(primers) = &options.trim_primers {
+            qseq = qseq
+                .into_iter()
+                .map(|fa| trim_primer_ends(fa, primers, options.primer_mismatches))
+                .collect();
+        }
+
+        let matrix = scoring_matrix(options.scoring_matrix.clone())?;
+        let genome_files = genome_fastas(&genomes);
+        let gfilep_f = genome_files[0].1.clone();
+        let gfilep_r = genome_files[0].2.clone();
+        let pool = thread_pool(options.num_threads)?;
+        let data: Vec<Reference> = process_fasta_output(
+            qseq.clone(),
+            String::from("queryF"),
+            String::from("queryR"),
+            &genome_files,
+            &options.contexts,
+            align_options,
+            thresholds,
+            &matrix,
+            pool.as_ref(),
+            progress,
+        )?;
+        let values = format_output(&genomes, &data, options.genome_header);
+        return Ok(Quma {
+            gfile_contents,
+            qfile_contents,
+            gseq,
+            genomes,
+            qseq,
+            gfilep_f,
+            gfilep_r,
+            data,
+            values,
+            genome_header: options.genome_header,
+            contexts: options.contexts.clone(),
+            clip_penalty: options.clip_penalty,
+            iupac_match: options.iupac_match,
+            bisulfite_match: true,
+            bisulfite_scoring: options.bisulfite_scoring,
+            fastq: options.fastq,
+            unconv_threshold: options.unconv_threshold,
+            pconv_threshold: options.pconv_threshold,
+            mismatch_threshold: options.mismatch_threshold,
+            percent_threshold: options.percent_threshold,
+            min_score: options.min_score,
+            expected_length: options.expected_length,
+            length_tolerance: options.length_tolerance,
+            gap_open: options.gap_open,
+            gap_extend: options.gap_extend,
+            align_mode: options.align_mode,
+            lowercase_islands: options.lowercase_islands,
+            islands,
+            trim_primers: options.trim_primers.clone(),
+            primer_mismatches: options.primer_mismatches,
+            min_ali_len: options.min_ali_len,
+            num_threads: options.num_threads,
+            strict_alphabet: options.strict_alphabet,
+            check_reverse: options.check_reverse,
+            exclude_ambiguous: options.exclude_ambiguous,
+            scoring_matrix: matrix.iter().copied().collect(),
+            alphabet,
+            filtered_characters,
+        });
+    }
+
+    /// Binary methylation matrix of non-excluded reads by genome CpGs
+    ///
+    /// Calls are 1 for a methylated CpG and 0 otherwise; the mask is 1 where
+    /// the read covers the CpG with a C or T. Ambiguous `YG` sites are never
+    /// covered. Rows are the reads assigned to `genome`.
+    ///
+    /// # Arguments
+    ///
+    /// * `genome` - reference name, or `None` for the first reference
+    ///
+    /// # Returns
+    ///
+    /// * `Result<(Array2<u8>, Array2<u8>), QumaError>` - methylation calls and
+    ///   coverage mask, or an error for an unknown reference
+    fn binary_matrix(&self, genome: Option<&str>) -> Result<(Array2<u8>, Array2<u8>), QumaError> {
+        let (name, seq) = self.reference(genome)?;
+        let sites = cpg_sites(seq);
+        let reads: Vec<&Reference> = self
+            .data
+            .iter()
+            .filter(|x| x.exc == 0 && x.genome == name)
+            .collect();
+
+        let mut calls = Array2::<u8>::zeros((reads.len(), sites.len()));
+        let mut mask = Array2::<u8>::zeros((reads.len(), sites.len()));
+        for (row, reference) in reads.iter().enumerate() {
+            for (pos, methylated) in methylation_calls(reference, &self.contexts) {
+                let col = match sites.binary_search(&pos) {
+                    Ok(col) => col,
+                    Err(_) => continue,
+                };
+                calls[(row, col)] = methylated as u8;
+                mask[(row, col)] = 1;
+            }
+        }
+
+        return Ok((calls, mask));
+    }
+
+    /// Pairwise methylation distance between non-excluded reads
+    ///
+    /// The distance is the Hamming distance over the CpGs covered by both
+    /// reads, divided by the number of such CpGs. Reads sharing no covered
+    /// CpG have a distance of NaN. Only reads assigned to `genome` are compared.
+    ///
+    /// # Arguments
+    ///
+    /// * `genome` - reference name, or `None` for the first reference
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Array2<f32>, QumaError>` - symmetric matrix of distances
+    ///   between reads, or an error for an unknown reference
+    fn distance_matrix(&self, genome: Option<&str>) -> Result<Array2<f32>, QumaError> {
+        let (name, _) = self.reference(genome)?;
+        let calls: Vec<BTreeMap<usize, bool>> = self
+            .data
+            .iter()
+            .filter(|x| x.exc == 0 && x.genome == name)
+            .map(|x| methylation_calls(x, &self.contexts))
+            .collect();
+
+        let mut distances = Array2::<f32>::zeros((calls.len(), calls.len()));
+        for i in 0..calls.len() {
+            for j in (i + 1)..calls.len() {
+                let mut shared = 0;
+                let mut differ = 0;
+                for (pos, methylated) in &calls[i] {
+                    if let Some(other) = calls[j].get(pos) {
+                        shared += 1;
+                        if methylated != other {
+                            differ += 1;
+                        }
+                    }
+                }
+
+                let mut distance = f32::NAN;
+                if shared > 0 {
+                    distance = differ as f32 / shared as f32;
+                }
+                distances[(i, j)] = distance;
+                distances[(j, i)] = distance;
+            }
+        }
+
+        return Ok(distances);
+    }
+
+    /// Exclusion thresholds the analysis was run with
+    fn thresholds(&self) -> Thresholds {
+        return Thresholds {
+            unconv: self.unconv_threshold,
+            pconv: self.pconv_threshold,
+            mis: self.mismatch_threshold,
+            perc: self.percent_threshold,
+            min_score: self.min_score,
+            expected_length: self.expected_length,
+            length_tolerance: self.length_tolerance,
+            min_ali_len: self.min_ali_len,
+            exclude_ambiguous: self.exclude_ambiguous,
+        };
+    }
+
+    /// Name and sequence of a reference genome
+    ///
+    /// # Arguments
+    ///
+    /// * `genome` - reference name, or `None` for the first reference
+    ///
+    /// # Returns
+    ///
+    /// * `Result<(&str, &str), QumaError>` - reference name and sequence, or
+    ///   an error if no reference has that name
+    fn reference(&self, genome: Option<&str>) -> Result<(&str, &str), QumaError> {
+        let found = match genome {
+            Some(genome) => self.genomes.iter().find(|(name, _)| name == genome),
+            None => self.genomes.first(),
+        };
+        return match found {
+            Some((name, seq)) => Ok((name, seq)),
+            None => Err(QumaError::UnknownReference(
+                genome.unwrap_or_default().to_string(),
+            )),
+        };
+    }
+
+    /// Sequence of the reference a read was assigned to
+    ///
+    /// # Arguments
+    ///
+    /// * `genome` - reference name
+    ///
+    /// # Returns
+    ///
+    /// * `&str` - reference sequence, empty for an unknown reference
+    fn genome_seq(&self, genome: &str) -> &str {
+        return self
+            .genomes
+            .iter()
+            .find(|(name, _)| name == genome)
+            .map_or("", |(_, seq)| seq);
+    }
+
+    /// Length of the reference a read was assigned to
+    ///
+    /// # Arguments
+    ///
+    /// * `genome` - reference name
+    ///
+    /// # Returns
+    ///
+    /// * `usize` - reference length, 0 for an unknown reference
+    fn genome_length(&self, genome: &str) -> usize {
+        return self.genome_seq(genome).len();
+    }
+}
+
+static RE1: Lazy<Regex> = Lazy::new(|| Regex::new(r"^[\r\s]+").unwrap());
+static RE2: Lazy<Regex> = Lazy::new(|| Regex::new(r"[\r\s]+$").unwrap());
+static RE3: Lazy<Regex> = Lazy::new(|| Regex::new(r"(\r|\n|\r\n){2}").unwrap());
+
+/// Parse genome file, removing white spaces and extra returns.
+///
+/// # Arguments
+///
+/// * `self` - Quma struct
+///
+/// # Returns
+///
+/// * `string` - parsed and curated string of genome sequence
+pub fn parse_genome(gfile_contents: &str) -> String {
+    return parse_genome_cased(gfile_contents, ALPHABET).to_uppercase();
+}
+
+/// Parse genome file as `parse_genome`, keeping the case of each base
+///
+/// # Arguments
+///
+/// * `gfile_contents` - genome file contents
+/// * `alphabet` - characters kept in the sequence
+///
+/// # Returns
+///
+/// * `string` - parsed genome sequence in its original case
+fn parse_genome_cased(gfile_contents: &str, alphabet: &str) -> String {
+    let out_one = RE1.replace_all(gfile_contents, "");
+    let out_two = RE2.replace_all(&out_one, "");
+    let out_three = RE3.replace_all(&out_two, "\n");
+
+    return parse_seq(&out_three, alphabet);
+}
+
+/// Split a genome file into named records, keeping the case of each base
+///
+/// Sequence before any header is named `genome`; records without sequence
+/// are dropped.
+///
+/// # Arguments
+///
+/// * `gfile_contents` - genome file contents
+/// * `alphabet` - characters kept in each sequence
+///
+/// # Returns
+///
+/// * `Vec<(String, String)>` - name and sequence of each record
+fn genome_records(gfile_contents: &str, alphabet: &str) -> Vec<(String, String)> {
+    let mut records = Vec::<(String, String)>::new();
+    let mut name = String::from("genome");
+    let mut lines = String::new();
+
+    for line in scrub_whitespace(gfile_contents).lines() {
+        if line.trim_start().starts_with('>') {
+            records.push((name, parse_genome_cased(&lines, alphabet)));
+            name = CLEAN1.replace(line.trim(), "").trim().to_string();
+            lines = String::new();
+        } else {
+            lines.push_str(line);
+            lines.push('\n');
+        }
+    }
+    records.push((name, parse_genome_cased(&lines, alphabet)));
+
+    records.retain(|(_, seq)| !seq.is_empty());
+    return records;
+}
+
+/// Parse a genome file holding one or more reference sequences
+///
+/// # Arguments
+///
+/// * `gfile_contents` - genome file contents
+///
+/// # Returns
+///
+/// * `Vec<(String, String)>` - name and upper-case sequence of each reference
+pub fn parse_genomes(gfile_contents: &str) -> Vec<(String, String)> {
+    return parse_genomes_in(gfile_contents, ALPHABET);
+}
+
+/// Parse a genome file as `parse_genomes`, keeping only `alphabet` characters
+///
+/// # Arguments
+///
+/// * `gfile_contents` - genome file contents
+/// * `alphabet` - characters kept in each sequence
+///
+/// # Returns
+///
+/// * `Vec<(String, String)>` - name and upper-case sequence of each reference
+fn parse_genomes_in(gfile_contents: &str, alphabet: &str) -> Vec<(String, String)> {
+    return genome_records(gfile_contents, alphabet)
+        .into_iter()
+        .map(|(name, seq)| (name, seq.to_uppercase()))
+        .collect();
+}
+
+/// Find CpG-island annotations given as lowercase genome bases
+///
+/// # Arguments
+///
+/// * `gfile_contents` - genome file contents
+/// * `alphabet` - characters kept in each sequence
+///
+/// # Returns
+///
+/// * `Vec<Vec<bool>>` - per genome record, whether each base is lowercase
+fn island_mask(gfile_contents: &str, alphabet: &str) -> Vec<Vec<bool>> {
+    return genome_records(gfile_contents, alphabet)
+        .iter()
+        .map(|(_, seq)| seq.bytes().map(|x| x.is_ascii_lowercase()).collect())
+        .collect();
+}
+
+// leading bytes of every gzip member
+static GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Decode file contents, decompressing gzip input
+///
+/// # Arguments
+///
+/// * `bytes` - raw file contents
+///
+/// # Returns
+///
+/// * `Result<String, QumaError>` - decoded text of the file
+fn decode_input(bytes: &[u8]) -> Result<String, QumaError> {
+    let mut contents = String::new();
+
+    if bytes.starts_with(&GZIP_MAGIC) {
+        MultiGzDecoder::new(bytes)
+            .read_to_string(&mut contents)
+            .map_err(|e| QumaError::Decode(e.to_string()))?;
+    } else {
+        contents =
+            String::from_utf8(bytes.to_vec()).map_err(|e| QumaError::Decode(e.to_string()))?;
+    }
+
+    return Ok(contents);
+}
+
+static SCRUB1: Lazy<Regex> = Lazy::new(|| Regex::new(r"\r\n\r\n").unwrap());
+
+static SCRUB2: Lazy<Regex> = Lazy::new(|| Regex::new(r"\n\n").unwrap());
+
+static SCRUB3: Lazy<Regex> = Lazy::new(|| Regex::new(r"\r\r").unwrap());
+
+static SCRUB4: Lazy<Regex> = Lazy::new(|| Regex::new(r"\r\n").unwrap());
+
+static SCRUB5: Lazy<Regex> = Lazy::new(|| Regex::new(r"\r").unwrap());
+
+/// Remove whitespace and repeated newlines.
+///
+/// # Arguments
+///
+/// * `string` - input fasta string
+///
+/// # Returns
+///
+/// * `string` - fasta string with whitespace removed
+fn scrub_whitespace(string: &str) -> String {
+    let trimmed = string.trim();
+    let trimmed = SCRUB1.replace_all(trimmed, "\r\n");
+    let trimmed = SCRUB2.replace_all(&trimmed, "\n");
+    let trimmed = SCRUB3.replace_all(&trimmed, "\r");
+    let trimmed = SCRUB4.replace_all(&trimmed, "\n");
+    let trimmed = SCRUB5.replace_all(&trimmed, "\n");
+    return trimmed.to_string();
+}
+
+static CLEAN1: Lazy<Regex> = Lazy::new(|| Regex::new(r"^>").unwrap());
+
+/// Parse bisulfite sequencing fasta file
+///
+/// # Returns
+///
+/// * `vector` - vector of Fasta structs of sequence reads
+pub fn parse_biseq(qfile_contents: &str) -> Vec<Fasta> {
+    let multi_clean = scrub_whitespace(qfile_contents);
+
+    let mut outcome = Vec::<Fasta>::new();
+
+    for line in multi_clean.lines() {
+        if line.starts_with('>') {
+            let processed = CLEAN1.replace_all(line, "");
+            let fa = Fasta {
+                com: processed.to_string(),
+                pos: String::from(""),
+                seq: String::from(""),
+                qual: None,
+                trim_start: 0,
+                trim_end: 0,
+            };
+            outcome.push(fa);
+        } else if let Some(fa) = outcome.last_mut() {
+            // sequences may be wrapped over several lines
+            fa.seq += &line.trim().to_uppercase();
+        }
+    }
+
+    // records without any sequence lines are not reads
+    outcome.retain(|fa| !fa.seq.is_empty());
+
+    return outcome;
+}
+
+/// Parse bisulfite sequencing fastq file
+///
+/// Records are four lines: `@` header, sequence, `+` separator and quality.
+/// A truncated final record is dropped.
+///
+/// # Returns
+///
+/// * `vector` - vector of Fasta structs of sequence reads
+pub fn parse_biseq_fastq(qfile_contents: &str) -> Vec<Fasta> {
+    let multi_clean = scrub_whitespace(qfile_contents);
+    let lines: Vec<&str> = multi_clean
+        .lines()
+        .map(|x| x.trim())
+        .filter(|x| !x.is_empty())
+        .collect();
+
+    let mut outcome = Vec::<Fasta>::new();
+
+    // quality lines may start with `@`, so records are read four lines at a time
+    let mut i = 0;
+    while i + 3 < lines.len() {
+        if lines[i].starts_with('@') && lines[i + 2].starts_with('+') {
+            let fa = Fasta {
+                com: lines[i][1..].to_string(),
+                pos: String::from(""),
+                seq: lines[i + 1].to_uppercase(),
+                qual: Some(lines[i + 3].to_string()),
+                trim_start: 0,
+                trim_end: 0,
+            };
+            outcome.push(fa);
+            i += 4;
+        } else {
+            i += 1;
+        }
+    }
+
+    return outcome;
+}
+
+// a leading header line, which may be the whole file
+static FILE_PATTERNS: Lazy<Regex> = Lazy::new(|| Regex::new(r"^\s*>[^\n]*\n?").unwrap());
+
+/// Extract sequence strings from the string of a text file
+///
+/// # Arguments
+///
+/// * `seq` - string of text file
+/// * `alphabet` - characters kept in the sequence
+///
+/// # Returns
+///
+/// * `string` - sequence string
+fn parse_seq(seq: &str, alphabet: &str) -> String {
+    let seq = seq.replace("\r\n", "\n").replace("\r", "\n");
+
+    // Different file patterns
+    let seq = FILE_PATTERNS.replace_all(&seq, r"");
+
+    // TODO: Did not implement unused fasta patterns
+
+    return check_char_in_allowed(&seq, alphabet);
+}
+
+/// Find characters of a genome file that are not sequence characters
+///
+/// Header lines and whitespace are skipped; anything else outside `alphabet`
+/// would be silently dropped by `parse_seq`.
+///
+/// # Arguments
+///
+/// * `contents` - genome file contents
+/// * `alphabet` - allowed sequence characters
+///
+/// # Returns
+///
+/// * `Vec<(usize, usize, char)>` - 1-based line, 1-based column and character
+///   of each disallowed character
+pub fn disallowed_characters(contents: &str, alphabet: &str) -> Vec<(usize, usize, char)> {
+    let mut found = Vec::<(usize, usize, char)>::new();
+    for (i, line) in contents.lines().enumerate() {
+        if line.trim_start().starts_with('>') {
+            continue;
+        }
+        for (j, c) in line.chars().enumerate() {
+            if !c.is_whitespace() && !alphabet.contains(c) {
+                found.push((i + 1, j + 1, c));
+            }
+        }
+    }
+    return found;
+}
+
+/// Return only charcters in string present in pattern
+///
+/// # Arguments
+///
+/// * `seq` - sequence string
+/// * `pattern` - string of allowed characters
+///
+/// # Returns
+///
+/// * `string` - sequence string with only allowed characters
+fn check_char_in_allowed(seq: &str, pattern: &str) -> String {
+    return seq.chars().filter(|&p| pattern.contains(p)).collect();
+}
+
+/// Choose the characters allowed in a genome
+///
+/// # Arguments
+///
+/// * `alphabet` - custom allowed characters, or `None` for `ALPHABET`
+///
+/// # Returns
+///
+/// * `Result<String, QumaError>` - allowed characters, or an error if the
+///   custom alphabet is empty
+fn allowed_alphabet(alphabet: Option<String>) -> Result<String, QumaError> {
+    match alphabet {
+        Some(alphabet) if alphabet.is_empty() => return Err(QumaError::EmptyAlphabet),
+        Some(alphabet) => return Ok(alphabet),
+        None => return Ok(ALPHABET.to_string()),
+    }
+}
+
+static RE4: Lazy<Regex> = Lazy::new(|| Regex::new(r"[0-9]| |\t|\n|\r|\f").unwrap());
+
+// bases per sequence line of fasta written by `fasta_make`
+static FASTA_WIDTH: usize = 60;
+
+/// Write a sequence string to a fasta-formatted text file contents
+///
+/// # Arguments
+///
+/// * `seq` - sequence string
+/// * `seq_name` - name of sequence
+///
+/// # Returns
+///
+/// * `string` - fasta-formatted text file contents, wrapped at `FASTA_WIDTH`
+pub fn fasta_make(seq: &str, seq_name: &str) -> String {
+    let seq = RE4.replace_all(seq, "");
+    let wrapped: Vec<&str> = seq
+        .as_bytes()
+        .chunks(FASTA_WIDTH)
+        .map(|line| std::str::from_utf8(line).unwrap_or_default())
+        .collect();
+
+    return format!(">{}\n{}", seq_name, wrapped.join("\n"));
+}
+
+/// Read the sequence of a single-record fasta file contents
+///
+/// # Arguments
+///
+/// * `contents` - fasta-formatted text, possibly wrapped over several lines
+///
+/// # Returns
+///
+/// * `String` - sequence lines after the header, joined
+fn fasta_sequence(contents: &str) -> String {
+    return contents.lines().skip(1).map(|x| x.trim()).collect();
+}
+
+/// Write the forward and reverse complement fasta of each genome reference
+///
+/// # Arguments
+///
+/// * `genomes` - name and sequence of each genome reference
+///
+/// # Returns
+///
+/// * `Vec<(String, String, String)>` - name, forward and reverse complement
+///   fasta of each genome
+pub fn genome_fastas(genomes: &[(String, String)]) -> Vec<(String, String, String)> {
+    return genomes
+        .iter()
+        .map(|(name, seq)| {
+            (
+                name.clone(),
+                fasta_make(seq, "genomeF"),
+                fasta_make(&rev_comp(seq), "genomeR"),
+            )
+        })
+        .collect();
+}
+
+/// Process fasta alignment
+///
+/// Reads are aligned in parallel; each worker scores with its own copy of the
+/// scoring matrix and the `lookup` table is read-only, so no mutable state is
+/// shared between threads. Identical reads are aligned once and share the
+/// result, keeping their own `pos` and `com`.
+///
+/// # Arguments
+///
+/// * `qseq` - vector of Fasta structs of query sequence
+/// * `qfile_f` - query sequence forward read
+/// * `qfile_r` - query sequence reverse complement
+/// * `genome_files` - name, forward and reverse complement fasta of each genome
+/// * `contexts` - cytosine contexts to call
+/// * `options` - alignment options
+/// * `thresholds` - exclusion thresholds
+/// * `matrix` - substitution scores in `MATRIX_ALPHABET` order
+/// * `pool` - thread pool to align in, or `None` for the global rayon pool
+/// * `progress` - number of distinct reads per progress report and the
+///   callback receiving `(reads_done, total_reads)`, called from this thread
+///
+/// # Returns
+///
+/// * `vector` - vector of Reference structs, in the order of `qseq`
+#[allow(clippy::too_many_arguments)]
+pub fn process_fasta_output(
+    qseq: Vec<Fasta>,
+    qfile_f: String,
+    qfile_r: String,
+    genome_files: &[(String, String, String)],
+    contexts: &[Context],
+    options: AlignOptions,
+    thresholds: Thresholds,
+    matrix: &Array2<i32>,
+    pool: Option<&rayon::ThreadPool>,
+    mut progress: Option<Progress>,
+) -> Result<Vec<Reference>, QumaError> {
+    // reads with the same sequence and trimming align identically
+    let mut unique = Vec::<Fasta>::new();
+    let mut seen = HashMap::<(String, usize, usize), usize>::new();
+    let mut shared = Vec::<usize>::new();
+    let mut reads = Vec::<Fasta>::new();
+    for (i, mut fa) in qseq.into_iter().enumerate() {
+        fa.pos = (i + 1).to_string();
+        let key = (fa.seq.clone(), fa.trim_start, fa.trim_end);
+        let index = *seen.entry(key).or_insert_with(|| {
+            unique.push(fa.clone());
+            unique.len() - 1
+        });
+        shared.push(index);
+        reads.push(fa);
+    }
+
+    let mut copies = vec![0; unique.len()];
+    for &index in &shared {
+        copies[index] += 1;
+    }
+
+    // align in chunks so progress is reported between them on this thread
+    let step = match &progress {
+        Some((every, _)) => cmp::max(*every, 1),
+        None => cmp::max(unique.len(), 1),
+    };
+    let mut aligned = Vec::<Reference>::with_capacity(unique.len());
+    let mut done = 0;
+    for (chunk, chunk_copies) in unique.chunks(step).zip(copies.chunks(step)) {
+        let align = || {
+            chunk
+                .par_iter()
+                .map_init(
+                    || read_aligner(options, matrix),
+                    |aligner, fa| {
+                        process_read(
+                            fa.clone(),
+                            &qfile_f,
+                            &qfile_r,
+                            genome_files,
+                            contexts,
+                            options,
+                            thresholds,
+                            aligner,
+                        )
+                    },
+                )
+                .collect::<Result<Vec<Reference>, QumaError>>()
+        };
+        let part = match pool {
+            Some(pool) => pool.install(align)?,
+            None => align()?,
+        };
+        aligned.extend(part);
+
+        done += chunk_copies.iter().sum::<usize>();
+        if let Some((_, report)) = progress.as_mut() {
+            report(done, reads.len());
+        }
+    }
+
+    return Ok(reads
+        .into_iter()
+        .zip(shared)
+        .map(|(fa, index)| {
+            let mut reference = aligned[index].clone();
+            reference.fasta = fa;
+            reference
+        })
+        .collect());
+}
+
+/// Align a single read against both strands of every genome and apply
+/// exclusion criteria
+///
+/// # Arguments
+///
+/// * `fa` - Fasta struct of the read
+/// * `qfile_f` - query sequence forward read
+/// * `qfile_r` - query sequence reverse complement
+/// * `genome_files` - name, forward and reverse complement fasta of each genome
+/// * `contexts` - cytosine contexts to call
+/// * `options` - alignment options
+/// * `thresholds` - exclusion thresholds
+/// * `aligner` - aligner built by `read_aligner` from `options`
+///
+/// # Returns
+///
+/// * `Reference` - Reference struct of the best alignment
+#[allow(clippy::too_many_arguments)]
+fn process_read(
+    fa: Fasta,
+    qfile_f: &str,
+    qfile_r: &str,
+    genome_files: &[(String, String, String)],
+    contexts: &[Context],
+    options: AlignOptions,
+    thresholds: Thresholds,
+    aligner: &mut ReadAligner,
+) -> Result<Reference, QumaError> {
+    check_bases(fa.seq.as_bytes(), &fa.com)?;
+    let seq_here = fa.trimmed_seq();
+
+    let qfile_f_processed = fasta_make(&seq_here, qfile_f);
+    let qfile_r_processed = fasta_make(&rev_comp(&seq_here), qfile_r);
+
+    // (result, read direction, genome direction, genome) of each alignment
+    let mut candidates = Vec::<(QumaResult, Direction, Direction, usize)>::new();
+    for (genome, (_, gfilep_f, gfilep_r)) in genome_files.iter().enumerate() {
+        for (gfile, gdir) in [
+            (gfilep_f, Direction::Forward),
+            (gfilep_r, Direction::Reverse),
+        ] {
+            let fwd_result = align_seq_and_generate_stats(
+                &qfile_f_processed,
+                gfile,
+                contexts,
+                options,
+                aligner,
+            )?;
+            candidates.push((fwd_result, Direction::Forward, gdir, genome));
+
+            // directional libraries never need the reverse complement
+            if options.check_reverse {
+                let rev_result = align_seq_and_generate_stats(
+                    &qfile_r_processed,
+                    gfile,
+                    contexts,
+                    options,
+                    aligner,
+                )?;
+                candidates.push((rev_result, Direction::Reverse, gdir, genome));
+            }
+        }
+    }
+
+    // orientations scoring below the minimum are not considered
+    let min_score = thresholds.min_score.unwrap_or(i32::MIN);
+    let mut pool: Vec<usize> = (0..candidates.len())
+        .filter(|&i| candidates[i].0.score >= min_score)
+        .collect();
+    let aligned = !pool.is_empty();
+    if !aligned {
+        pool = (0..candidates.len()).collect();
+    }
+
+    let ranked: Vec<(QumaResult, Direction, Direction)> = pool
+        .iter()
+        .map(|&i| (candidates[i].0.clone(), candidates[i].1, candidates[i].2))
+        .collect();
+    let mut best = pool[find_best_dataset(&ranked)];
+
+    // a tie across genomes goes to the first tied genome by name
+    let tied: Vec<usize> = pool
+        .iter()
+        .copied()
+        .filter(|&i| same_rank(&candidates[i].0, &candidates[best].0))
+        .collect();
+    let ambiguous = tied.iter().any(|&i| candidates[i].3 != candidates[best].3);
+    if ambiguous {
+        let first = tied
+            .iter()
+            .map(|&i| candidates[i].3)
+            .min_by_key(|&genome| &genome_files[genome].0)
+            .unwrap_or(candidates[best].3);
+        let tied: Vec<usize> = tied
+            .into_iter()
+            .filter(|&i| candidates[i].3 == first)
+            .collect();
+        let ranked: Vec<(QumaResult, Direction, Direction)> = tied
+            .iter()
+            .map(|&i| (candidates[i].0.clone(), candidates[i].1, candidates[i].2))
+            .collect();
+        best = tied[find_best_dataset(&ranked)];
+    }
+
+    let (this_result, final_direction, genome_direction, genome) = candidates.swap_remove(best);
+    debug!(
+        "read {}: dir {} gdir {} score {} perc {}",
+        fa.com,
+        final_direction.sign(),
+        genome_direction.sign(),
+        this_result.score,
+        this_result.perc
+    );
+    if this_result.ali_len == 0 {
+        warn!("read {} did not align to the genome", fa.com);
+    }
+
+    let (genome_name, gfilep_f, _) = &genome_files[genome];
+    let glen = fasta_sequence(gfilep_f).len();
+    let this_result = forward_positions(this_result, genome_direction, glen);
+
+    let mut this_ref = Reference {
+        fasta: fa,
+        res: this_result.clone(),
+        dir: final_direction,
+        gdir: genome_direction,
+        exc: 0,
+        reason: None,
+        genome: genome_name.clone(),
+        ambiguous_reference: ambiguous,
+    };
+
+    let reason = if !aligned {
+        Some("unaligned")
+    } else if length_outlier(seq_here.len(), thresholds) {
+        Some("length")
+    } else if ambiguous && thresholds.exclude_ambiguous {
+        Some("ambiguous")
+    } else {
+        alignment_exclusion(&this_ref.res, &thresholds)
+    };
+
+    if let Some(reason) = reason {
+        debug!("read {} excluded: {}", this_ref.fasta.com, reason);
+        this_ref.exc = 1;
+        this_ref.reason = Some(reason.to_string());
+    }
+
+    return Ok(this_ref);
+}
+
+/// Check the statistics of a read's best alignment against the thresholds
+///
+/// # Arguments
+///
+/// * `res` - QumaResult struct of the best alignment
+/// * `thresholds` - exclusion thresholds
+///
+/// # Returns
+///
+/// * `Option<&'static str>` - first threshold the alignment fails, or `None`
+fn alignment_exclusion(res: &QumaResult, thresholds: &Thresholds) -> Option<&'static str> {
+    if res.ali_len < thresholds.min_ali_len {
+        return Some("ali_len");
+    } else if res.unconv > thresholds.unconv {
+        return Some("unconv");
+    } else if res.pconv > thresholds.pconv {
+        return Some("pconv");
+    } else if res.ali_mis > thresholds.mis {
+        return Some("mismatch");
+    } else if res.perc > thresholds.perc {
+        return Some("perc");
+    }
+    return None;
+}
+
+/// Check that every base of a read is in the scoring matrix alphabet
+///
+/// # Arguments
+///
+/// * `seq` - read sequence
+/// * `name` - read name used in the error message
+///
+/// # Returns
+///
+/// * `Result<(), QumaError>` - error naming the first unexpected character
+fn check_bases(seq: &[u8], name: &str) -> Result<(), QumaError> {
+    match seq.iter().find(|&&x| lookup(x).is_none()) {
+        Some(&base) => Err(QumaError::UnexpectedCharacter(
+            base as char,
+            name.to_string(),
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Count mismatches between two equal-length sequences, ignoring case
+///
+/// # Arguments
+///
+/// * `a` - first sequence
+/// * `b` - second sequence
+///
+/// # Returns
+///
+/// * `usize` - number of differing positions
+fn hamming(a: &[u8], b: &[u8]) -> usize {
+    return a
+        .iter()
+        .zip(b.iter())
+        .filter(|(x, y)| !x.eq_ignore_ascii_case(y))
+        .count();
+}
+
+/// Record primer matches at the ends of a read as trimmed bases
+///
+/// The forward primer is matched at the start of the read and the reverse
+/// complement of the reverse primer at its end. `seq` is left untouched;
+/// `trim_start` and `trim_end` mark the bases excluded from alignment.
+///
+/// # Arguments
+///
+/// * `fa` - read to trim
+/// * `primers` - forward and reverse primer
+/// * `mismatches` - mismatches allowed when matching a primer
+///
+/// # Returns
+///
+/// * `Fasta` - read with trim offsets set
+fn trim_primer_ends(mut fa: Fasta, primers: &(String, String), mismatches: usize) -> Fasta {
+    let seq = fa.seq.as_bytes();
+    let forward = primers.0.as_bytes();
+    let reverse = rev_comp(&primers.1);
+    let reverse = reverse.as_bytes();
+
+    if !forward.is_empty()
+        && forward.len() <= seq.len()
+        && hamming(&seq[..forward.len()], forward) <= mismatches
+    {
+        fa.trim_start = forward.len();
+    }
+    if !reverse.is_empty()
+        && fa.trim_start + reverse.len() <= seq.len()
+        && hamming(&seq[seq.len() - reverse.len()..], reverse) <= mismatches
+    {
+        fa.trim_end = reverse.len();
+    }
+
+    return fa;
+}
+
+/// Check whether a read length deviates too far from the expected length
+///
+/// # Arguments
+///
+/// * `len` - read length
+/// * `thresholds` - exclusion thresholds with the expected length and tolerance
+///
+/// # Returns
+///
+/// * `bool` - true if an expected length is set and the read deviates from it
+///   by more than the tolerance percentage
+fn length_outlier(len: usize, thresholds: Thresholds) -> bool {
+    match thresholds.expected_length {
+        Some(expected) if expected > 0 => {
+            let deviation = (len as f32 - expected as f32).abs() / expected as f32;
+            return 100.0 * deviation > thresholds.length_tolerance;
+        }
+        _ => return false,
+    }
+}
+
+/// Return reverse complement of sequence
+///
+/// # Arguments
+///
+/// * `seq` - sequence string
+///
+/// # Returns
+///
+/// * `string` - reverse complement of sequence
+pub fn rev_comp(seq: &str) -> String {
+    return seq.chars().rev().map(complement).collect();
+}
+
+/// Return complement of an IUPAC base, preserving case
+///
+/// # Arguments
+///
+/// * `base` - base character
+///
+/// # Returns
+///
+/// * `char` - complementary base, or the input unchanged if not a base
+fn complement(base: char) -> char {
+    match base {
+        'A' => 'T',
+        'C' => 'G',
+        'G' => 'C',
+        'T' => 'A',
+        'U' => 'A',
+        'R' => 'Y',
+        'Y' => 'R',
+        'M' => 'K',
+        'W' => 'W',
+        'S' => 'S',
+        'K' => 'M',
+        'D' => 'H',
+        'H' => 'D',
+        'B' => 'V',
+        'V' => 'B',
+        'N' => 'N',
+        'a' => 't',
+        'c' => 'g',
+        'g' => 'c',
+        't' => 'a',
+        'u' => 'a',
+        'r' => 'y',
+        'y' => 'r',
+        'm' => 'k',
+        'w' => 'w',
+        's' => 's',
+        'k' => 'm',
+        'd' => 'h',
+        'h' => 'd',
+        'b' => 'v',
+        'v' => 'b',
+        'n' => 'n',
+        _ => base,
+    }
+}
+
+/// Find pairwise alignment substrings
+///
+/// # Arguments
+///
+/// * `alignment` - alignment object
+/// * `bio_gseq` - genomic sequence
+/// * `bio_qseq` - query sequence
+///
+/// # Returns
+///
+/// * `tuple` - tuple of String, String genomic and query aligned substrings,
+///   gapped with `-` so that both share alignment columns
+fn matching_substrings(
+    alignment: &Alignment,
+    bio_gseq: &[u8],
+    bio_qseq: &[u8],
+) -> (String, String) {
+    let mut g_substring = String::new();
+    let mut q_substring = String::new();
+
+    let mut x = alignment.xstart;
+    let mut y = alignment.ystart;
+    for operation in &alignment.operations {
+        match operation {
+            AlignmentOperation::Match | AlignmentOperation::Subst => {
+                g_substring.push(bio_gseq[x] as char);
+                q_substring.push(bio_qseq[y] as char);
+                x += 1;
+                y += 1;
+            }
+            AlignmentOperation::Del => {
+                g_substring.push('-');
+                q_substring.push(bio_qseq[y] as char);
+                y += 1;
+            }
+            AlignmentOperation::Ins => {
+                g_substring.push(bio_gseq[x] as char);
+                q_substring.push('-');
+                x += 1;
+            }
+            AlignmentOperation::Xclip(_) | AlignmentOperation::Yclip(_) => continue,
+        }
+    }
+
+    return (g_substring, q_substring);
+}
+
+/// Remove leading and trailing gap columns from an alignment
+///
+/// # Arguments
+///
+/// * `g_ali` - aligned genome sequence
+/// * `q_ali` - aligned query sequence, the same length as `g_ali`
+///
+/// # Returns
+///
+/// * `(String, String, usize, usize)` - trimmed genome and query alignment,
+///   and the genome and query bases removed from the start
+fn trim_terminal_gaps(g_ali: &str, q_ali: &str) -> (String, String, usize, usize) {
+    let columns: Vec<(u8, u8)> = g_ali.bytes().zip(q_ali.bytes()).collect();
+    let aligned = |&(g, q): &(u8, u8)| g != b'-' && q != b'-';
+
+    let start = columns.iter().position(aligned).unwrap_or(columns.len());
+    let end = columns.iter().rposition(aligned).map_or(start, |x| x + 1);
+
+    let g_skipped = columns[..start].iter().filter(|x| x.0 != b'-').count();
+    let q_skipped = columns[..start].iter().filter(|x| x.1 != b'-').count();
+
+    return (
+        g_ali[start..end].to_string(),
+        q_ali[start..end].to_string(),
+        g_skipped,
+        q_skipped,
+    );
+}
+
+fn quma_score(matrix: &Array2<i32>, a: u8, b: u8) -> i32 {
+    // sequences are checked before alignment; anything else scores as N
+    let a = lookup(a).unwrap_or(N_INDEX);
+    let b = lookup(b).unwrap_or(N_INDEX);
+
+    matrix[(a, b)]
+}
+
+/// Score a genome base against a query base, allowing bisulfite conversion
+///
+/// A genomic C read as T is scored as a match; all other pairs use `matrix`.
+///
+/// # Arguments
+///
+/// * `matrix` - substitution scores in `MATRIX_ALPHABET` order
+/// * `a` - genome base
+/// * `b` - query base
+///
+/// # Returns
+///
+/// * `i32` - substitution score
+fn bisulfite_score(matrix: &Array2<i32>, a: u8, b: u8) -> i32 {
+    if a == b'C' && b == b'T' {
+        return quma_score(matrix, b'C', b'C');
+    }
+
+    return quma_score(matrix, a, b);
+}
+
+/// Build the scoring matrix from flattened values, or the default `MATRIX`
+///
+/// # Arguments
+///
+/// * `values` - 16x16 substitution scores in `MATRIX_ALPHABET` order, row by
+///   row, or `None` for the default
+///
+/// # Returns
+///
+/// * `Result<Array2<i32>, QumaError>` - scoring matrix, or an error if the
+///   number of values is wrong
+pub fn scoring_matrix(values: Option<Vec<i32>>) -> Result<Array2<i32>, QumaError> {
+    match values {
+        Some(values) => {
+            let len = values.len();
+            return Array2::from_shape_vec((MATRIX_SIZE, MATRIX_SIZE), values)
+                .map_err(|_| QumaError::MatrixSize(len));
+        }
+        None => return Ok(MATRIX.clone()),
+    }
+}
+
+// distinct reads per progress report and the callback receiving
+// (reads_done, total_reads)
+pub type Progress<'a> = (usize, &'a mut dyn FnMut(usize, usize));
+
+// pairwise aligner of reads against the genome, reused across reads
+pub type ReadAligner = Aligner<Box<dyn Fn(u8, u8) -> i32 + Send>>;
+
+// methylation calls and coverage mask, one row per read
+pub type BinaryRows = (Vec<Vec<u8>>, Vec<Vec<u8>>);
+
+/// Build the aligner for a set of alignment options
+///
+/// The aligner keeps its dynamic-programming buffers between alignments, so
+/// one aligner is shared by all reads processed on a thread.
+///
+/// # Arguments
+///
+/// * `options` - alignment options
+/// * `matrix` - substitution scores in `MATRIX_ALPHABET` order
+///
+/// # Returns
+///
+/// * `ReadAligner` - aligner with the scoring of `options`
+pub fn read_aligner(options: AlignOptions, matrix: &Array2<i32>) -> ReadAligner {
+    // See https://docs.rs/bio/latest/src/bio/scores/blosum62.rs.html#89-94
+    let matrix = matrix.clone();
+    let score_fn: Box<dyn Fn(u8, u8) -> i32 + Send> = match options.bisulfite_scoring {
+        true => Box::new(move |a, b| bisulfite_score(&matrix, a, b)),
+        false => Box::new(move |a, b| quma_score(&matrix, a, b)),
+    };
+
+    let scoring = Scoring::new(options.gap_open, options.gap_extend, score_fn);
+    let scoring = match (options.clip_penalty, options.align_mode) {
+        // genome ends are free to overhang, read ends are clipped at a cost
+        (Some(penalty), _) => scoring.xclip(0).yclip(cmp::min(penalty, 0)),
+        // bio's semiglobal keeps x (the genome) global, so clip explicitly
+        (None, AlignMode::Semiglobal) => scoring.xclip(0).yclip(MIN_SCORE),
+        // local and global set their own clipping for each alignment
+        (None, _) => scoring,
+    };
+
+    return Aligner::with_scoring(scoring);
+}
+
+/// Run pairwise sequence alignment
+///
+/// # Arguments
+///
+/// * `gfile` - genomic sequence file contents
+/// * `qfile` - sequencing read(s) file contents
+/// * `contexts` - cytosine contexts to call
+/// * `options` - alignment options
+/// * `aligner` - aligner built by `read_aligner` from the same options
+///
+/// # Returns
+///
+/// * `Result<QumaResult, QumaError>` - alignment result struct
+pub fn align_seq_and_generate_stats(
+    qfile: &str,
+    gfile: &str,
+    contexts: &[Context],
+    options: AlignOptions,
+    aligner: &mut ReadAligner,
+) -> Result<QumaResult, QumaError> {
+    let mut this_result = QumaResult {
+        q_ali: "".to_string(),
+        g_ali: "".to_string(),
+        val: "".to_string(),
+        perc: 0.0,
+        pconv: 0.0,
+        gap: 0,
+        menum: 0,
+        unconv: 0,
+        conv: 0,
+        quma_match: 0,
+        ali_mis: 0,
+        ali_len: 0,
+        g_start: 0,
+        q_start: 0,
+        conversion_sites: 0,
+        cpg_positions: Vec::new(),
+        score: 0,
+        conv_eff: 0.0,
+        midline: "".to_string(),
+        no_cpg: false,
+        mismatch_positions: Vec::new(),
+    };
+
+    let gseq = fasta_sequence(gfile);
+    let qseq = fasta_sequence(qfile);
+    if gseq.is_empty() {
+        return Err(QumaError::EmptyGenome);
+    }
+    if qseq.is_empty() {
+        return Err(QumaError::EmptyRead);
+    }
+    let bio_gseq = gseq.as_bytes();
+    let bio_qseq = qseq.as_bytes();
+
+    // clipping for the custom modes is configured by `read_aligner`
+    let bio_alignments = match (options.clip_penalty, options.align_mode) {
+        (Some(_), _) | (None, AlignMode::Semiglobal) => aligner.custom(bio_gseq, bio_qseq),
+        (None, AlignMode::Local) => aligner.local(bio_gseq, bio_qseq),
+        (None, AlignMode::Global) => aligner.global(bio_gseq, bio_qseq),
+    };
+
+    let (genome_ali, query_ali) = matching_substrings(&bio_alignments, bio_gseq, bio_qseq);
+    let (genome_ali, query_ali) = (genome_ali.replace(" ", "-"), query_ali.replace(" ", "-"));
+    let (g_ali, q_ali, g_skipped, q_skipped) = trim_terminal_gaps(&genome_ali, &query_ali);
+    this_result.g_start = (bio_alignments.xstart + g_skipped) as i32;
+    this_result.q_start = (bio_alignments.ystart + q_skipped) as i32;
+    this_result.score = bio_alignments.score;
+
+    this_result.q_ali = q_ali;
+    this_result.g_ali = g_ali;
+
+    let final_result = process_alignment_matches(this_result, contexts, options);
+
+    return Ok(final_result);
+}
+
+/// Count dinucleotides in an aligned sequence, ignoring gaps
+///
+/// # Arguments
+///
+/// * `seq` - aligned sequence string
+///
+/// # Returns
+///
+/// * `HashMap<String, i32>` - count of each dinucleotide
+fn dinucleotide_counts(seq: &str) -> HashMap<String, i32> {
+    let bases: Vec<u8> = seq.bytes().filter(|&x| x != b'-').collect();
+
+    let mut counts = HashMap::new();
+    for pair in bases.windows(2) {
+        let key = String::from_utf8(pair.to_vec()).unwrap();
+        *counts.entry(key).or_insert(0) += 1;
+    }
+
+    return counts;
+}
+
+/// Classify the cytosine context at a position of a genome sequence
+///
+/// A `Y` (C or T) followed by `G` may be a CpG and is classified as one;
+/// other ambiguity codes are never treated as cytosines.
+///
+/// # Arguments
+///
+/// * `seq` - genome sequence without gaps
+/// * `i` - position to classify
+///
+/// # Returns
+///
+/// * `Option<Context>` - context of the cytosine, if `seq[i]` is one
+fn cytosine_context(seq: &[u8], i: usize) -> Option<Context> {
+    if seq[i] == b'Y' && seq.get(i + 1) == Some(&b'G') {
+        return Some(Context::CpG);
+    }
+    if seq[i] != b'C' {
+        return None;
+    }
+
+    let next = *seq.get(i + 1)?;
+    if next == b'G' {
+        return Some(Context::CpG);
+    } else if !b"ACT".contains(&next) {
+        return None;
+    }
+
+    let after = *seq.get(i + 2)?;
+    if after == b'G' {
+        return Some(Context::CHG);
+    } else if b"ACT".contains(&after) {
+        return Some(Context::CHH);
+    }
+    return None;
+}
+
+/// Count the cytosine contexts of a genome sequence
+///
+/// # Arguments
+///
+/// * `seq` - genome sequence
+///
+/// # Returns
+///
+/// * `HashMap<String, i32>` - count of each cytosine context
+fn context_counts(seq: &str) -> HashMap<String, i32> {
+    let seq = seq.as_bytes();
+
+    let mut counts = HashMap::new();
+    for context in [Context::CpG, Context::CHG, Context::CHH] {
+        counts.insert(context.label().to_string(), 0);
+    }
+    for i in 0..seq.len() {
+        if let Some(context) = cytosine_context(seq, i) {
+            *counts.get_mut(context.label()).unwrap() += 1;
+        }
+    }
+
+    return counts;
+}
+
+/// Find the CpG sites of a genome sequence
+///
+/// # Arguments
+///
+/// * `seq` - genome sequence
+///
+/// # Returns
+///
+/// * `Vec<usize>` - genome coordinates of each CpG cytosine
+fn cpg_sites(seq: &str) -> Vec<usize> {
+    let seq = seq.as_bytes();
+    return (0..seq.len())
+        .filter(|&i| cytosine_context(seq, i) == Some(Context::CpG))
+        .collect();
+}
+
+/// Find the alignment column and genome coordinate of each methylation call
+///
+/// Columns are paired with `cpg_positions`, so entries run parallel to the
+/// characters of `val`.
+///
+/// # Arguments
+///
+/// * `reference` - Reference struct
+/// * `contexts` - cytosine contexts the read was called in
+///
+/// # Returns
+///
+/// * `Vec<(usize, usize)>` - alignment column and forward genome coordinate per call
+fn call_columns(reference: &Reference, contexts: &[Context]) -> Vec<(usize, usize)> {
+    let res = &reference.res;
+    return context_columns(&res.g_ali, contexts)
+        .into_iter()
+        .filter(|&column| column < res.q_ali.len())
+        .zip(res.cpg_positions.iter().map(|&pos| pos as usize))
+        .collect();
+}
+
+/// Find the alignment column and genome coordinate of each CpG call
+///
+/// # Arguments
+///
+/// * `reference` - Reference struct
+/// * `contexts` - cytosine contexts the read was called in
+///
+/// # Returns
+///
+/// * `Vec<(usize, usize)>` - alignment column and forward genome coordinate per CpG
+fn cpg_call_columns(reference: &Reference, contexts: &[Context]) -> Vec<(usize, usize)> {
+    let cpgs = context_columns(&reference.res.g_ali, &[Context::CpG]);
+    return call_columns(reference, contexts)
+        .into_iter()
+        .filter(|(column, _)| cpgs.binary_search(column).is_ok())
+        .collect();
+}
+
+/// Find the genome coordinate and methylation call of each CpG in an alignment
+///
+/// # Arguments
+///
+/// * `reference` - Reference struct
+/// * `contexts` - cytosine contexts the read was called in
+///
+/// # Returns
+///
+/// * `Vec<(usize, char)>` - forward genome coordinate and `call_symbol` per CpG
+fn cpg_calls(reference: &Reference, contexts: &[Context]) -> Vec<(usize, char)> {
+    let q_ali = reference.res.q_ali.as_bytes();
+    let g_ali = reference.res.g_ali.as_bytes();
+
+    return cpg_call_columns(reference, contexts)
+        .into_iter()
+        .map(|(column, pos)| (pos, call_symbol(g_ali[column], q_ali[column])))
+        .collect();
+}
+
+/// Methylation call of the query base aligned to a genome cytosine
+///
+/// # Arguments
+///
+/// * `genome` - genome base, `C` or an ambiguous `Y`
+/// * `query` - aligned query base
+///
+/// # Returns
+///
+/// * `char` - `1` methylated or `0` unmethylated at a genome C, `m` or `u` at
+///   a genome `Y`, otherwise the query base
+fn call_symbol(genome: u8, query: u8) -> char {
+    match (genome, query) {
+        (b'Y', b'C') => return 'm',
+        (b'Y', b'T') => return 'u',
+        (_, b'C') => return '1',
+        (_, b'T') => return '0',
+        (_, other) => return other as char,
+    }
+}
+
+/// Base quality of the read base aligned at an alignment column
+///
+/// # Arguments
+///
+/// * `reference` - Reference struct
+/// * `column` - alignment column
+///
+/// # Returns
+///
+/// * `Option<u8>` - phred quality, or `None` without fastq qualities
+fn call_quality(reference: &Reference, column: usize) -> Option<u8> {
+    let qual = reference.fasta.qual.as_ref()?.as_bytes();
+    let q_ali = reference.res.q_ali.as_bytes();
+
+    let offset = q_ali[..column].iter().filter(|&x| x != &b'-').count();
+    let mut index = reference.res.q_start as usize + offset;
+    if reference.dir == Direction::Reverse {
+        // the aligned query is the reverse complement of the trimmed read
+        let end = reference
+            .fasta
+            .seq
+            .len()
+            .checked_sub(reference.fasta.trim_end)?;
+        index = end.checked_sub(index + 1)?;
+    } else {
+        index += reference.fasta.trim_start;
+    }
+
+    return qual.get(index).map(|x| x.saturating_sub(33));
+}
+
+/// Find the methylation state of each covered CpG in an alignment
+///
+/// Ambiguous `YG` sites are left out, as they are from `conv` and `unconv`.
+///
+/// # Arguments
+///
+/// * `reference` - Reference struct
+/// * `contexts` - cytosine contexts the read was called in
+///
+/// # Returns
+///
+/// * `BTreeMap<usize, bool>` - methylation state by genome coordinate
+fn methylation_calls(reference: &Reference, contexts: &[Context]) -> BTreeMap<usize, bool> {
+    let mut calls = BTreeMap::<usize, bool>::new();
+    for (pos, call) in cpg_calls(reference, contexts) {
+        if call == '1' || call == '0' {
+            calls.insert(pos, call == '1');
+        }
+    }
+    return calls;
+}
+
+/// Distances between consecutive covered CpGs of a read
+///
+/// # Arguments
+///
+/// * `reference` - Reference struct of the read
+/// * `contexts` - cytosine contexts the read was called in
+///
+/// # Returns
+///
+/// * `Vec<usize>` - spacing between neighbouring CpGs in genome order
+fn cpg_spacing(reference: &Reference, contexts: &[Context]) -> Vec<usize> {
+    let sites: Vec<usize> = methylation_calls(reference, contexts).into_keys().collect();
+    return sites.windows(2).map(|x| x[1] - x[0]).collect();
+}
+
+/// Find alignment columns of genomic cytosines in the requested contexts
+///
+/// # Arguments
+///
+/// * `g_ali` - aligned genome sequence
+/// * `contexts` - cytosine contexts to report
+///
+/// # Returns
+///
+/// * `Vec<usize>` - alignment columns of matching cytosines
+fn context_columns(g_ali: &str, contexts: &[Context]) -> Vec<usize> {
+    let g_ali = g_ali.as_bytes();
+
+    // contexts are defined on the genome, so skip over alignment gaps
+    let columns: Vec<usize> = (0..g_ali.len()).filter(|&i| g_ali[i] != b'-').collect();
+    let bases: Vec<u8> = columns.iter().map(|&i| g_ali[i]).collect();
+
+    let mut found = Vec::<usize>::new();
+    for (k, &column) in columns.iter().enumerate() {
+        match cytosine_context(&bases, k) {
+            Some(context) if contexts.contains(&context) => found.push(column),
+            _ => continue,
+        }
+    }
+
+    return found;
+}
+
+/// Bases represented by an IUPAC nucleotide code
+///
+/// # Arguments
+///
+/// * `code` - IUPAC code
+///
+/// # Returns
+///
+/// * `&[u8]` - bases represented by the code, empty if not a code
+fn iupac_bases(code: u8) -> &'static [u8] {
+    match code {
+        b'A' => b"A",
+        b'C' => b"C",
+        b'G' => b"G",
+        b'T' | b'U' => b"T",
+        b'R' => b"AG",
+        b'Y' => b"CT",
+        b'S' => b"CG",
+        b'W' => b"AT",
+        b'K' => b"GT",
+        b'M' => b"AC",
+        b'B' => b"CGT",
+        b'D' => b"AGT",
+        b'H' => b"ACT",
+        b'V' => b"ACG",
+        b'N' => b"ACGT",
+        _ => b"",
+    }
+}
+
+/// Whether two IUPAC codes can represent the same base
+///
+/// # Arguments
+///
+/// * `a` - first IUPAC code
+/// * `b` - second IUPAC code
+///
+/// # Returns
+///
+/// * `bool` - true if the codes share a base
+fn iupac_compatible(a: u8, b: u8) -> bool {
+    let b_bases = iupac_bases(b);
+    return iupac_bases(a).iter().any(|x| b_bases.contains(x));
+}
+
+/// Count matching positions of an alignment
+///
+/// # Arguments
+///
+/// * `q_ali` - aligned query sequence
+/// * `g_ali` - aligned genome sequence
+/// * `bisulfite_match` - count query T against genome C as a match
+/// * `iupac_match` - count query ambiguity codes compatible with the genome as matches
+///
+/// # Returns
+///
+/// * `i32` - number of matching positions
+fn count_matches(q_ali: &[u8], g_ali: &[u8], bisulfite_match: bool, iupac_match: bool) -> i32 {
+    let mut this_sum = 0;
+    let it = q_ali.iter().zip(g_ali.iter());
+    for (a, b) in it {
+        if a == b
+            || (bisulfite_match && *a == b'T' && *b == b'C')
+            || (iupac_match && iupac_compatible(*a, *b))
+        {
+            this_sum += 1;
+        }
+    }
+
+    return this_sum;
+}
+
+/// Find alignment columns holding a sequencing or alignment error
+///
+/// A genome C read as T is a bisulfite conversion, not an error; gap columns
+/// are also skipped.
+///
+/// # Arguments
+///
+/// * `q_ali` - aligned query sequence
+/// * `g_ali` - aligned genome sequence
+/// * `iupac_match` - treat query ambiguity codes compatible with the genome as matches
+///
+/// # Returns
+///
+/// * `Vec<i32>` - 0-based alignment column of each mismatch
+fn mismatch_columns(q_ali: &[u8], g_ali: &[u8], iupac_match: bool) -> Vec<i32> {
+    let mut columns = Vec::<i32>::new();
+    for (i, (&a, &b)) in q_ali.iter().zip(g_ali.iter()).enumerate() {
+        if a == b || a == b'-' || b == b'-' || (a == b'T' && b == b'C') {
+            continue;
+        }
+        if iupac_match && iupac_compatible(a, b) {
+            continue;
+        }
+        columns.push(i as i32);
+    }
+
+    return columns;
+}
+
+/// Count bisulfite conversion of non-CpG genomic cytosines in an alignment
+///
+/// # Arguments
+///
+/// * `res` - QumaResult struct with aligned sequences
+///
+/// # Returns
+///
+/// * `(i32, i32)` - converted (T in the read) and informative (C or T in the
+///   read) non-CpG cytosines
+fn non_cpg_conversion(res: &QumaResult) -> (i32, i32) {
+    let q_ali = res.q_ali.as_bytes();
+
+    let mut converted = 0;
+    let mut informative = 0;
+    for column in context_columns(&res.g_ali, &[Context::CHG, Context::CHH]) {
+        match q_ali.get(column) {
+            Some(b'T') => {
+                converted += 1;
+                informative += 1;
+            }
+            Some(b'C') => informative += 1,
+            _ => continue,
+        }
+    }
+
+    return (converted, informative);
+}
+
+/// Call methylation at genomic cytosines of the requested contexts
+///
+/// Calls at candidate CpGs with an ambiguous genome base (`YG`) are recorded
+/// as `m` (read C) or `u` (read T) and are not counted in `conv`, `unconv` or
+/// `menum`, since the genome may carry a T there. Matches are counted by
+/// `count_matches` alone, so calls leave `quma_match` unchanged.
+///
+/// # Arguments
+///
+/// * `result` - QumaResult struct with aligned sequences
+/// * `contexts` - cytosine contexts to call
+///
+/// # Returns
+///
+/// * `QumaResult` - QumaResult struct with populated methylation calls
+fn call_methylation(mut result: QumaResult, contexts: &[Context]) -> QumaResult {
+    result.val = String::new();
+    result.menum = 0;
+    result.unconv = 0;
+    result.conv = 0;
+    result.cpg_positions = Vec::new();
+
+    let q_ali = result.q_ali.as_bytes();
+    let g_ali = result.g_ali.as_bytes();
+    for column in context_columns(&result.g_ali, contexts) {
+        let base = match q_ali.get(column) {
+            Some(&base) => base,
+            None => continue,
+        };
+
+        let offset = g_ali[..column].iter().filter(|&x| x != &b'-').count();
+        result.cpg_positions.push(result.g_start + offset as i32);
+
+        let call = call_symbol(g_ali[column], base);
+        match call {
+            '0' => result.unconv += 1,
+            '1' => {
+                result.conv += 1;
+                result.menum += 1;
+            }
+            _ => (),
+        }
+        result.val.push(call);
+    }
+
+    result.no_cpg = result.cpg_positions.is_empty();
+    if result.val.is_empty() {
+        result.val = "-".to_string();
+    }
+
+    return result;
+}
+
+/// Count substitutions between aligned genome and query sequences
+///
+/// Columns with a gap or an ambiguity code on either side are skipped.
+///
+/// # Arguments
+///
+/// * `g_ali` - aligned genome sequence
+/// * `q_ali` - aligned query sequence
+///
+/// # Returns
+///
+/// * `Array2<i32>` - 4x4 counts indexed by genome base then query base (`ACGT`)
+fn substitution_counts(g_ali: &str, q_ali: &str) -> Array2<i32> {
+    let index = |x: u8| b"ACGT".iter().position(|&y| y == x);
+
+    let mut counts = Array2::<i32>::zeros((4, 4));
+    for (g, q) in g_ali.bytes().zip(q_ali.bytes()) {
+        if let (Some(i), Some(j)) = (index(g), index(q)) {
+            counts[(i, j)] += 1;
+        }
+    }
+
+    return counts;
+}
+
+/// Process alignment data to populate results dictionary
+///
+/// # Arguments
+///
+/// * `result` - QumaResult struct
+/// * `contexts` - cytosine contexts to call
+/// * `options` - alignment options
+///
+/// # Returns
+///
+/// * `QumaResult` - QumaResult struct with populated results dictionary
+fn process_alignment_matches(
+    mut result: QumaResult,
+    contexts: &[Context],
+    options: AlignOptions,
+) -> QumaResult {
+    let g_ali = result.g_ali.as_bytes();
+    let q_ali = result.q_ali.as_bytes();
+
+    result.ali_len = q_ali.len() as i32;
+    result.midline = alignment_midline(g_ali, q_ali);
+    result.quma_match = count_matches(q_ali, g_ali, options.bisulfite_match, options.iupac_match);
+    result.mismatch_positions = mismatch_columns(q_ali, g_ali, options.iupac_match);
+
+    let g_ali_count = g_ali.iter().filter(|&x| x == &b'-').count();
+    let q_ali_count = q_ali.iter().filter(|&x| x == &b'-').count();
+
+    result.gap = cmp::max(
+        g_ali_count.try_into().unwrap(),
+        q_ali_count.try_into().unwrap(),
+    );
+
+    let (converted, conversion_sites) = non_cpg_conversion(&result);
+    result.conversion_sites = conversion_sites;
+    result.conv_eff = percentage(converted, conversion_sites, PercentType::Total).unwrap_or(0.0);
+
+    let result = call_methylation(result, contexts);
+
+    let results = generate_summary_stats(result);
+    return results;
+}
+
+/// Build a BLAST-style midline marking each column of an alignment
+///
+/// # Arguments
+///
+/// * `g_ali` - aligned genome sequence
+/// * `q_ali` - aligned query sequence
+///
+/// # Returns
+///
+/// * `String` - `|` for identical bases, `:` for a genome C read as T and a
+///   space for mismatches and gaps
+fn alignment_midline(g_ali: &[u8], q_ali: &[u8]) -> String {
+    return g_ali
+        .iter()
+        .zip(q_ali.iter())
+        .map(|(&g, &q)| match (g, q) {
+            (b'-', _) | (_, b'-') => ' ',
+            (b'C', b'T') => ':',
+            (g, q) if g == q => '|',
+            _ => ' ',
+        })
+        .collect();
+}
+
+/// Re-count matches of a stored alignment under new match rules
+///
+/// # Arguments
+///
+/// * `result` - QumaResult struct with aligned sequences and methylation calls
+/// * `bisulfite_match` - count query T against genome C as a match
+/// * `iupac_match` - count query ambiguity codes compatible with the genome as matches
+///
+/// # Returns
+///
+/// * `QumaResult` - QumaResult struct with updated match statistics
+fn rescore_matches(mut result: QumaResult, bisulfite_match: bool, iupac_match: bool) -> QumaResult {
+    let q_ali = result.q_ali.as_bytes();
+    let g_ali = result.g_ali.as_bytes();
+
+    result.quma_match = count_matches(q_ali, g_ali, bisulfite_match, iupac_match);
+    result.mismatch_positions = mismatch_columns(q_ali, g_ali, iupac_match);
+
+    return generate_summary_stats(result);
+}
+
+/// Map call positions of a reverse-strand alignment to forward genome coordinates
+///
+/// A reverse-strand CpG maps onto the forward CpG whose G it starts on; any
+/// other reverse-strand cytosine maps onto the forward base it pairs with.
+///
+/// # Arguments
+///
+/// * `result` - QumaResult struct with positions on the aligned genome strand
+/// * `gdir` - genome direction of the alignment
+/// * `glen` - length of the genome sequence
+///
+/// # Returns
+///
+/// * `QumaResult` - QumaResult struct with forward genome positions
+fn forward_positions(mut result: QumaResult, gdir: Direction, glen: usize) -> QumaResult {
+    if gdir == Direction::Reverse {
+        let bases: Vec<u8> = result.g_ali.bytes().filter(|&x| x != b'-').collect();
+        let g_start = result.g_start;
+        result.cpg_positions = result
+            .cpg_positions
+            .iter()
+            .map(
+                |&pos| match cytosine_context(&bases, (pos - g_start) as usize) {
+                    Some(Context::CpG) => glen as i32 - 2 - pos,
+                    _ => glen as i32 - 1 - pos,
+                },
+            )
+            .collect();
+    }
+    return result;
+}
+
+/// Helper to generate summary statistics in QumaResult struct
+///
+/// # Arguments
+///
+/// * `result` - QumaResult struct
+///
+/// # Returns
+///
+/// * `QumaResult` - QumaResult struct with summary statistics
+fn generate_summary_stats(mut result: QumaResult) -> QumaResult {
+    result.pconv = percentage(result.conv, result.unconv, PercentType::Sum).unwrap_or(0.0);
+    result.perc = percentage(result.quma_match, result.ali_len, PercentType::Total).unwrap_or(0.0);
+    result.ali_mis = result.ali_len - result.quma_match;
+    return result;
+}
+
+// denominators of `percentage`: `a + b` for Sum, `b` alone for Total
+#[derive(Clone, Copy)]
+enum PercentType {
+    Sum,
+    Total,
+}
+
+/// Helper to return percentages
+///
+/// # Arguments
+///
+/// * `a` - numerator
+/// * `b` - second count, added to `a` for `Sum` or the whole for `Total`
+/// * `calc_type` - type of calculation to perform
+///
+/// # Returns
+///
+/// * `Option<f32>` - percentage, or `None` if the denominator is zero
+fn percentage(a: i32, b: i32, calc_type: PercentType) -> Option<f32> {
+    let total = match calc_type {
+        PercentType::Sum => a + b,
+        PercentType::Total => b,
+    };
+    if total == 0 {
+        return None;
+    }
+    return Some(100.0 * a as f32 / total as f32);
+}
+
+/// Helper to find best data returned
+///
+/// Candidates are ranked by match percentage, then by alignment length, then
+/// by fewer mismatches, then by alignment score. Exact ties prefer the forward
+/// read and then the forward genome strand; any remaining tie keeps the
+/// earlier candidate.
+///
+/// # Arguments
+///
+/// * `candidates` - non-empty slice of (quma result, read direction, genome
+///   direction) of each alignment
+///
+/// # Returns
+///
+/// * `usize` - index of the best candidate
+fn find_best_dataset(candidates: &[(QumaResult, Direction, Direction)]) -> usize {
+    let mut best_index = 0;
+    for (index, candidate) in candidates.iter().enumerate().skip(1) {
+        let best = &candidates[best_index];
+        let (res, best_res) = (&candidate.0, &best.0);
+        let better = res
+            .perc
+            .partial_cmp(&best_res.perc)
+            .unwrap_or(cmp::Ordering::Equal)
+            .then(res.ali_len.cmp(&best_res.ali_len))
+            .then(best_res.ali_mis.cmp(&res.ali_mis))
+            .then(res.score.cmp(&best_res.score))
+            .then(candidate.1.sign().cmp(&best.1.sign()))
+            .then(candidate.2.sign().cmp(&best.2.sign()))
+            == cmp::Ordering::Greater;
+        if better {
+            best_index = index;
+        }
+    }
+
+    return best_index;
+}
+
+/// Check whether two alignments rank equally in `find_best_dataset`
+///
+/// Read and genome direction are not compared.
+///
+/// # Arguments
+///
+/// * `a` - quma result of one alignment
+/// * `b` - quma result of the other alignment
+///
+/// # Returns
+///
+/// * `bool` - whether match percentage, alignment length, mismatches and
+///   score are all equal
+fn same_rank(a: &QumaResult, b: &QumaResult) -> bool {
+    return a.perc == b.perc
+        && a.ali_len == b.ali_len
+        && a.ali_mis == b.ali_mis
+        && a.score == b.score;
+}
+
+// number of tab-terminated columns in each data row of `format_output`
+static OUTPUT_COLUMNS: usize = 16;
+
+/// Process program output into quma-formatted string
+///
+/// Rows are grouped by the genome reference each read is assigned to.
+///
+/// # Arguments
+///
+/// * `genomes` - name and sequence of each genome reference
+/// * `data` - vector of Reference structs
+/// * `genome_header` - whether to prepend each genome line
+///
+/// # Returns
+///
+/// * `String` - tabular quma-formatted string
+pub fn format_output(
+    genomes: &[(String, String)],
+    data: &[Reference],
+    genome_header: bool,
+) -> String {
+    let mut output_holder: Vec<String> = Vec::new();
+
+    for (name, gseq) in genomes {
+        if genome_header {
+            // a single reference keeps the original `genome` label
+            let mut label = "genome";
+            if genomes.len() > 1 {
+                label = name.as_str();
+            }
+            // pad the five genome fields with empty columns to match the data rows
+            let padding = "\t".repeat(OUTPUT_COLUMNS - 5);
+            output_holder.push(format!("{}\t0\t{}\t1\t0\t{}\n", label, gseq, padding));
+        }
+
+        for reference in data.iter().filter(|x| &x.genome == name) {
+            output_holder.push(format!("{}\t", reference.fasta.pos));
+            output_holder.push(format!("{}\t", reference.fasta.com));
+            output_holder.push(format!("{}\t", reference.fasta.seq));
+            output_holder.push(format!("{}\t", reference.res.q_ali));
+            output_holder.push(format!("{}\t", reference.res.g_ali));
+            output_holder.push(format!("{}\t", reference.res.ali_len));
+            output_holder.push(format!("{}\t", reference.res.ali_mis));
+            output_holder.push(format!("{}\t", reference.res.perc));
+            output_holder.push(format!("{}\t", reference.res.gap));
+            output_holder.push(format!("{}\t", reference.res.menum));
+            output_holder.push(format!("{}\t", reference.res.unconv));
+            output_holder.push(format!("{}\t", reference.res.conv));
+            output_holder.push(format!("{}\t", reference.res.pconv));
+            output_holder.push(format!("{}\t", reference.res.val));
+            output_holder.push(format!("{}\t", reference.dir.sign()));
+            output_holder.push(format!("{}\t", reference.gdir.sign()));
+            output_holder.push("\n".to_string());
+        }
+    }
+
+    return output_holder.join("");
+}
+
+// header of each column of a `format_table` row
+static TABLE_HEADER: [&str; 17] = [
+    "pos",
+    "comment",
+    "seq",
+    "q_ali",
+    "g_ali",
+    "ali_len",
+    "ali_mis",
+    "perc",
+    "gap",
+    "menum",
+    "unconv",
+    "conv",
+    "pconv",
+    "val",
+    "dir",
+    "gdir",
+    "positions",
+];
+
+/// Process program output into a delimited table with a header row
+///
+/// The `positions` column lists the forward genome coordinate of each call in
+/// `val`, separated by `;`, so calls at the same site share a label across
+/// reads.
+///
+/// # Arguments
+///
+/// * `data` - vector of Reference structs
+/// * `csv` - separate columns with commas, quoting the comment field,
+///   instead of tabs
+///
+/// # Returns
+///
+/// * `String` - header line followed by one row per read
+pub fn format_table(data: &[Reference], csv: bool) -> String {
+    let delimiter = match csv {
+        true => ",",
+        false => "\t",
+    };
+
+    let mut table = TABLE_HEADER.join(delimiter);
+    table.push('\n');
+
+    for reference in data {
+        let mut comment = reference.fasta.com.clone();
+        if csv && comment.contains([',', '"', '\n']) {
+            comment = format!("\"{}\"", comment.replace('"', "\"\""));
+        }
+        let positions: Vec<String> = reference
+            .res
+            .cpg_positions
+            .iter()
+            .map(|x| x.to_string())
+            .collect();
+        let row = [
+            reference.fasta.pos.clone(),
+            comment,
+            reference.fasta.seq.clone(),
+            reference.res.q_ali.clone(),
+            reference.res.g_ali.clone(),
+            reference.res.ali_len.to_string(),
+            reference.res.ali_mis.to_string(),
+            reference.res.perc.to_string(),
+            reference.res.gap.to_string(),
+            reference.res.menum.to_string(),
+            reference.res.unconv.to_string(),
+            reference.res.conv.to_string(),
+            reference.res.pconv.to_string(),
+            reference.res.val.clone(),
+            reference.dir.sign().to_string(),
+            reference.gdir.sign().to_string(),
+            positions.join(";"),
+        ];
+        table.push_str(&row.join(delimiter));
+        table.push('\n');
+    }
+
+    return table;
+}
+
+// SAM flags set by `sam_record`
+static SAM_UNMAPPED: u16 = 0x4;
+static SAM_REVERSE: u16 = 0x10;
+static SAM_QC_FAIL: u16 = 0x200;
+
+/// Build the CIGAR operations of an alignment, including clipped read ends
+///
+/// # Arguments
+///
+/// * `res` - QumaResult struct with aligned sequences
+/// * `read_len` - length of the aligned read
+/// * `hard_clips` - bases trimmed before alignment at the start and end of
+///   the aligned read
+///
+/// # Returns
+///
+/// * `Vec<(usize, char)>` - run length and operation, in alignment order
+fn cigar_operations(
+    res: &QumaResult,
+    read_len: usize,
+    hard_clips: (usize, usize),
+) -> Vec<(usize, char)> {
+    let mut ops = Vec::<(usize, char)>::new();
+    let mut push = |op: char, len: usize| {
+        if len == 0 {
+            return;
+        }
+        match ops.last_mut() {
+            Some(last) if last.1 == op => last.0 += len,
+            _ => ops.push((len, op)),
+        }
+    };
+
+    let q_start = res.q_start as usize;
+    let q_used = res.q_ali.bytes().filter(|&x| x != b'-').count();
+    push('H', hard_clips.0);
+    push('S', q_start);
+    for (g, q) in res.g_ali.bytes().zip(res.q_ali.bytes()) {
+        match (g, q) {
+            (b'-', _) => push('I', 1),
+            (_, b'-') => push('D', 1),
+            _ => push('M', 1),
+        }
+    }
+    push('S', read_len.saturating_sub(q_start + q_used));
+    push('H', hard_clips.1);
+
+    return ops;
+}
+
+/// Format the alignment of a read as a SAM record
+///
+/// Records are reported against the forward genome strand, so alignments to
+/// the reverse strand are reverse-complemented.
+///
+/// # Arguments
+///
+/// * `reference` - Reference struct
+/// * `glen` - length of the genome sequence the read is assigned to
+///
+/// # Returns
+///
+/// * `String` - tab-separated SAM record without a line ending
+fn sam_record(reference: &Reference, glen: usize) -> String {
+    let fa = &reference.fasta;
+    let res = &reference.res;
+    let qname = fa.com.split_whitespace().next().unwrap_or("*");
+
+    let trimmed = fa.trimmed_seq();
+    let mut qual = fa.qual.as_ref().map(|x| {
+        let end = x.len().saturating_sub(fa.trim_end);
+        x[cmp::min(fa.trim_start, end)..end].to_string()
+    });
+
+    let mut flag: u16 = 0;
+    if reference.exc != 0 {
+        flag |= SAM_QC_FAIL;
+    }
+    if res.ali_len == 0 {
+        // unmapped reads keep their own orientation
+        flag |= SAM_UNMAPPED;
+        return format!(
+            "{}\t{}\t*\t0\t0\t*\t*\t0\t0\t{}\t{}",
+            qname,
+            flag,
+            trimmed,
+            qual.unwrap_or_else(|| "*".to_string())
+        );
+    }
+    if reference.dir != reference.gdir {
+        flag |= SAM_REVERSE;
+    }
+
+    // the read as aligned, then as seen from the forward genome strand
+    let mut seq = trimmed.clone();
+    let mut clips = (fa.trim_start, fa.trim_end);
+    if reference.dir == Direction::Reverse {
+        seq = rev_comp(&seq);
+        qual = qual.map(|x| x.chars().rev().collect());
+        clips = (clips.1, clips.0);
+    }
+    let mut ops = cigar_operations(res, trimmed.len(), clips);
+    if reference.gdir == Direction::Reverse {
+        seq = rev_comp(&seq);
+        qual = qual.map(|x| x.chars().rev().collect());
+        ops.reverse();
+    }
+    let qual = qual.unwrap_or_else(|| "*".to_string());
+
+    // leftmost forward genome coordinate covered by the alignment
+    let g_span = res.g_ali.bytes().filter(|&x| x != b'-').count();
+    let mut start = res.g_start as usize;
+    if reference.gdir == Direction::Reverse {
+        start = glen.saturating_sub(start + g_span);
+    }
+
+    let cigar: String = ops
+        .iter()
+        .map(|(len, op)| format!("{}{}", len, op))
+        .collect();
+
+    return format!(
+        "{}\t{}\t{}\t{}\t255\t{}\t*\t0\t0\t{}\t{}\tAS:i:{}",
+        qname,
+        flag,
+        reference.genome,
+        start + 1,
+        cigar,
+        seq,
+        qual,
+        res.score
+    );
+}
+
+/// Group non-excluded reads by their methylation pattern
+///
+/// # Arguments
+///
+/// * `data` - vector of Reference structs
+///
+/// # Returns
+///
+/// * `Vec<(String, i32)>` - `val` pattern and read count, sorted by
+///   descending count and then by pattern
+fn epialleles(data: &[Reference]) -> Vec<(String, i32)> {
+    let mut counts = HashMap::<String, i32>::new();
+    for reference in data.iter().filter(|x| x.exc == 0) {
+        *counts.entry(reference.res.val.clone()).or_insert(0) += 1;
+    }
+
+    let mut patterns: Vec<(String, i32)> = counts.into_iter().collect();
+    patterns.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+
+    return patterns;
+}
+
+/// Long table of CpG methylation calls of non-excluded reads
+///
+/// # Arguments
+///
+/// * `data` - vector of Reference structs
+/// * `genome` - name of the reference whose reads are listed
+/// * `contexts` - cytosine contexts the reads were called in
+///
+/// # Returns
+///
+/// * `String` - tab-separated rows, one per read and CpG: read position, read
+///   name, reference name, genome coordinate, call (as `call_symbol`) and
+///   base quality (`.` without fastq)
+fn long_table(data: &[Reference], genome: &str, contexts: &[Context]) -> String {
+    let mut table = String::new();
+
+    for reference in data.iter().filter(|x| x.exc == 0 && x.genome == genome) {
+        let q_ali = reference.res.q_ali.as_bytes();
+        let g_ali = reference.res.g_ali.as_bytes();
+        for (column, site) in cpg_call_columns(reference, contexts) {
+            let call = call_symbol(g_ali[column], q_ali[column]);
+            let qual = match call_quality(reference, column) {
+                Some(qual) => qual.to_string(),
+                None => NOT_COVERED.to_string(),
+            };
+            table.push_str(&format!(
+                "{}\t{}\t{}\t{}\t{}\t{}\n",
+                reference.fasta.pos, reference.fasta.com, genome, site, call, qual
+            ));
+        }
+    }
+
+    return table;
+}
+
+/// Pool methylation of non-excluded reads by the call positions of `val`
+///
+/// Only `1` and `0` calls are pooled, so ambiguous `YG` sites and other
+/// aligned bases do not count as coverage.
+///
+/// # Arguments
+///
+/// * `data` - vector of Reference structs
+/// * `genome` - name of the reference whose reads are pooled
+///
+/// # Returns
+///
+/// * `BTreeMap<usize, (i32, i32)>` - methylated and covering read counts per
+///   position in `cpg_positions`
+fn site_methylation(data: &[Reference], genome: &str) -> BTreeMap<usize, (i32, i32)> {
+    let mut sites = BTreeMap::<usize, (i32, i32)>::new();
+
+    for reference in data.iter().filter(|x| x.exc == 0 && x.genome == genome) {
+        let calls = reference.res.val.chars().zip(&reference.res.cpg_positions);
+        for (call, &pos) in calls {
+            if call != '1' && call != '0' {
+                continue;
+            }
+            let site = sites.entry(pos as usize).or_insert((0, 0));
+            if call == '1' {
+                site.0 += 1;
+            }
+            site.1 += 1;
+        }
+    }
+
+    return sites;
+}
+
+/// Format pooled CpG methylation as bedGraph
+///
+/// Coordinates are 0-based half-open spans of the CpG cytosine and the
+/// level is the fraction of covering reads methylated at that site.
+///
+/// # Arguments
+///
+/// * `sites` - methylated and covering read counts per CpG
+/// * `chrom` - chromosome name for the first column
+/// * `offset` - genomic coordinate of the first genome base
+///
+/// # Returns
+///
+/// * `String` - tab-separated bedGraph lines
+fn format_bedgraph(sites: &BTreeMap<usize, (i32, i32)>, chrom: &str, offset: usize) -> String {
+    let mut bedgraph = String::new();
+
+    for (pos, (methylated, covered)) in sites {
+        let level = *methylated as f32 / *covered as f32;
+        let start = offset + pos;
+        bedgraph.push_str(&format!("{}\t{}\t{}\t{}\n", chrom, start, start + 1, level));
+    }
+
+    return bedgraph;
+}
+
+/// Downsample non-excluded reads so both orientations contribute equally
+///
+/// The overrepresented orientation keeps its reads with the longest
+/// alignments. If either orientation has no reads, all reads are kept.
+///
+/// # Arguments
+///
+/// * `data` - vector of Reference structs
+///
+/// # Returns
+///
+/// * `Vec<Reference>` - non-excluded reads with balanced orientations
+fn balanced_reads(data: &[Reference]) -> Vec<Reference> {
+    let (mut forward, mut reverse): (Vec<Reference>, Vec<Reference>) = data
+        .iter()
+        .filter(|x| x.exc == 0)
+        .cloned()
+        .partition(|x| x.dir == Direction::Forward);
+
+    let keep = cmp::min(forward.len(), reverse.len());
+    if keep == 0 {
+        forward.append(&mut reverse);
+        return forward;
+    }
+
+    for reads in [&mut forward, &mut reverse] {
+        reads.sort_by_key(|x| cmp::Reverse(x.res.ali_len));
+        reads.truncate(keep);
+    }
+
+    forward.append(&mut reverse);
+    return forward;
+}
+
+/// Pool methylation over the calls of non-excluded reads
+///
+/// # Arguments
+///
+/// * `data` - vector of Reference structs
+///
+/// # Returns
+///
+/// * `f32` - pooled methylation percentage, 0 without called sites
+fn pooled_methylation(data: &[Reference]) -> f32 {
+    let mut conv = 0;
+    let mut unconv = 0;
+    for reference in data.iter().filter(|x| x.exc == 0) {
+        conv += reference.res.conv;
+        unconv += reference.res.unconv;
+    }
+    return percentage(conv, unconv, PercentType::Sum).unwrap_or(0.0);
+}
+
+/// Pool methylation over the CpG calls of non-excluded reads
+///
+/// # Arguments
+///
+/// * `data` - vector of Reference structs
+/// * `identity_weighted` - weight each read by its identity (`perc` / 100)
+///
+/// # Returns
+///
+/// * `f32` - pooled methylation percentage
+fn weighted_methylation(data: &[Reference], identity_weighted: bool) -> f32 {
+    if !identity_weighted {
+        return pooled_methylation(data);
+    }
+
+    let mut methylated: f32 = 0.0;
+    let mut total: f32 = 0.0;
+
+    for reference in data.iter().filter(|x| x.exc == 0) {
+        let weight = reference.res.perc / 100.0;
+        methylated += weight * reference.res.conv as f32;
+        total += weight * (reference.res.conv + reference.res.unconv) as f32;
+    }
+
+    if total > 0.0 {
+        return 100.0 * methylated / total;
+    }
+    return 0.0;
+}
+
+/// Aggregate read counts and pooled methylation
+///
+/// # Arguments
+///
+/// * `data` - vector of Reference structs
+///
+/// # Returns
+///
+/// * `Summary` - read counts and methylation of non-excluded reads
+fn summary(data: &[Reference]) -> Summary {
+    let mut included_reads = 0;
+    let mut conv = 0;
+    let mut unconv = 0;
+
+    for reference in data.iter().filter(|x| x.exc == 0) {
+        included_reads += 1;
+        conv += reference.res.conv;
+        unconv += reference.res.unconv;
+    }
+
+    return Summary {
+        total_reads: data.len() as i32,
+        included_reads,
+        excluded_reads: data.len() as i32 - included_reads,
+        cpgs_observed: conv + unconv,
+        methylated: conv,
+        methylation: pooled_methylation(data),
+    };
+}
+
+/// Count distinct molecules among non-excluded reads
+///
+/// # Arguments
+///
+/// * `data` - vector of Reference structs
+///
+/// # Returns
+///
+/// * `i32` - number of distinct analyzed sequences, with reverse reads
+///   reverse complemented to the forward orientation
+fn unique_count(data: &[Reference]) -> i32 {
+    let mut molecules = HashSet::<String>::new();
+    for reference in data.iter().filter(|x| x.exc == 0) {
+        let seq = reference.fasta.trimmed_seq();
+        let molecule = match reference.dir {
+            Direction::Forward => seq,
+            Direction::Reverse => rev_comp(&seq),
+        };
+        molecules.insert(molecule);
+    }
+    return molecules.len() as i32;
+}
+
+/// Pooled and per-read mean methylation of non-excluded reads
+///
+/// Reads without called sites are left out of the mean.
+///
+/// # Arguments
+///
+/// * `data` - vector of Reference structs
+///
+/// # Returns
+///
+/// * `(f32, f32)` - pooled methylation percentage and mean of per-read
+///   percentages, both 0 without called sites
+fn methylation_levels(data: &[Reference]) -> (f32, f32) {
+    let mut levels = Vec::<f32>::new();
+    for reference in data.iter().filter(|x| x.exc == 0) {
+        if let Some(level) = percentage(reference.res.conv, reference.res.unconv, PercentType::Sum)
+        {
+            levels.push(level);
+        }
+    }
+
+    let mut mean = 0.0;
+    if !levels.is_empty() {
+        mean = levels.iter().sum::<f32>() / levels.len() as f32;
+    }
+
+    return (pooled_methylation(data), mean);
+}
+
+// two-sided 95% critical value for the conversion efficiency interval
+static CONVERSION_CI_Z: f32 = 1.96;
+
+/// Pool non-CpG conversion of non-excluded reads with a confidence interval
+///
+/// Uses the Wilson score interval for a binomial proportion.
+///
+/// # Arguments
+///
+/// * `data` - vector of Reference structs
+///
+/// # Returns
+///
+/// * `(f32, f32, f32)` - conversion percentage and the lower and upper bounds
+///   of its confidence interval, all 0 without informative cytosines
+fn conversion_efficiency(data: &[Reference]) -> (f32, f32, f32) {
+    let mut converted = 0;
+    let mut informative = 0;
+    for reference in data.iter().filter(|x| x.exc == 0) {
+        let (read_converted, read_informative) = non_cpg_conversion(&reference.res);
+        converted += read_converted;
+        informative += read_informative;
+    }
+
+    if informative == 0 {
+        return (0.0, 0.0, 0.0);
+    }
+
+    let n = informative as f32;
+    let p = converted as f32 / n;
+    let z2 = CONVERSION_CI_Z * CONVERSION_CI_Z;
+
+    let center = (p + z2 / (2.0 * n)) / (1.0 + z2 / n);
+    let half_width =
+        CONVERSION_CI_Z * (p * (1.0 - p) / n + z2 / (4.0 * n * n)).sqrt() / (1.0 + z2 / n);
+
+    return (
+        100.0 * p,
+        100.0 * (center - half_width).max(0.0),
+        100.0 * (center + half_width).min(1.0),
+    );
+}
+
+// two-sided 95% critical value for the strand bias z-test
+static STRAND_BIAS_Z: f32 = 1.96;
+
+/// Compare methylation of forward and reverse reads
+///
+/// Uses a two-proportion z-test on the pooled CpG calls of each strand.
+///
+/// # Arguments
+///
+/// * `data` - vector of Reference structs
+///
+/// # Returns
+///
+/// * `StrandBias` - per-strand methylation and strand bias flag
+fn strand_bias(data: &[Reference]) -> StrandBias {
+    let mut forward_reads = 0;
+    let mut reverse_reads = 0;
+    let mut fwd_conv = 0;
+    let mut fwd_unconv = 0;
+    let mut rev_conv = 0;
+    let mut rev_unconv = 0;
+
+    for reference in data.iter().filter(|x| x.exc == 0) {
+        if reference.dir == Direction::Forward {
+            forward_reads += 1;
+            fwd_conv += reference.res.conv;
+            fwd_unconv += reference.res.unconv;
+        } else {
+            reverse_reads += 1;
+            rev_conv += reference.res.conv;
+            rev_unconv += reference.res.unconv;
+        }
+    }
+
+    let fwd_total = fwd_conv + fwd_unconv;
+    let rev_total = rev_conv + rev_unconv;
+
+    let mut z_score: f32 = 0.0;
+    if fwd_total > 0 && rev_total > 0 {
+        let p_fwd = fwd_conv as f32 / fwd_total as f32;
+        let p_rev = rev_conv as f32 / rev_total as f32;
+        let pooled = (fwd_conv + rev_conv) as f32 / (fwd_total + rev_total) as f32;
+        let se =
+            (pooled * (1.0 - pooled) * (1.0 / fwd_total as f32 + 1.0 / rev_total as f32)).sqrt();
+        if se > 0.0 {
+            z_score = (p_fwd - p_rev) / se;
+        }
+    }
+
+    let forward_methylation = percentage(fwd_conv, fwd_unconv, PercentType::Sum).unwrap_or(0.0);
+    let reverse_methylation = percentage(rev_conv, rev_unconv, PercentType::Sum).unwrap_or(0.0);
+
+    return StrandBias {
+        forward_reads,
+        reverse_reads,
+        forward_methylation,
+        reverse_methylation,
+        z_score,
+        biased: z_score.abs() > STRAND_BIAS_Z,
+    };
+}
+
+// Report disallowed characters of a genome file as (line, column, character)
+#[pyfunction]
+#[pyo3(signature = (gfile_contents, alphabet = None))]
+fn check_alphabet(
+    gfile_contents: &str,
+    alphabet: Option<String>,
+) -> PyResult<Vec<(usize, usize, char)>> {
+    let alphabet = allowed_alphabet(alphabet)?;
+    return Ok(disallowed_characters(gfile_contents, &alphabet));
+}
+
+// Run quma and return the quma object
+#[pyfunction]
+fn quma(gseq: String, qseq: String) -> PyResult<Quma> {
+    return Ok(Quma::analyze(&gseq, &qseq)?);
+}
+
+/// A Python module implemented in Rust.
+#[pymodule]
 fn rust_quma(_py: Python<'_>, m: &PyModule) -> PyResult<()> {
     m.add_class::<Quma>()?;
+    m.add_class::<QumaBuilder>()?;
+    m.add_class::<QumaStream>()?;
     m.add_class::<Reference>()?;
+    m.add_class::<QumaResult>()?;
+    m.add_class::<Fasta>()?;
+    m.add_class::<StrandBias>()?;
+    m.add_class::<Summary>()?;
+    m.add_class::<Context>()?;
+    m.add_class::<AlignMode>()?;
+    m.add_class::<Direction>()?;
     m.add_function(wrap_pyfunction!(quma, m)?)?;
+    m.add_function(wrap_pyfunction!(check_alphabet, m)?)?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // amplicon with CpGs at 0-based positions 8, 17, 28 and 40
+    static GENOME: &str = ">amplicon\nTTAGGATACGAATTGTACGTTAAGGTCACGATTAGTGCATCGATTAGGTTA\n";
+
+    /// Bisulfite-convert a sequence, keeping the C of each methylated CpG
+    ///
+    /// # Arguments
+    ///
+    /// * `seq` - genome sequence
+    /// * `methylated` - state of each CpG of `seq`, in order
+    ///
+    /// # Returns
+    ///
+    /// * `String` - converted read sequence
+    fn bisulfite(seq: &str, methylated: &[bool]) -> String {
+        let bases = seq.as_bytes();
+        let mut sites = methylated.iter();
+        let mut read = String::new();
+        for (i, &base) in bases.iter().enumerate() {
+            let cpg = base == b'C' && bases.get(i + 1) == Some(&b'G');
+            match base {
+                b'C' if cpg && *sites.next().unwrap() => read.push('C'),
+                b'C' => read.push('T'),
+                other => read.push(other as char),
+            }
+        }
+        return read;
+    }
+
+    /// Genome sequence of `GENOME` without its header
+    fn genome_seq() -> String {
+        return GENOME.lines().nth(1).unwrap().to_string();
+    }
+
+    /// Fasta records named `r0`, `r1`, ... for each read sequence
+    ///
+    /// # Arguments
+    ///
+    /// * `reads` - read sequences
+    ///
+    /// # Returns
+    ///
+    /// * `String` - query fasta contents
+    fn fasta(reads: &[String]) -> String {
+        return reads
+            .iter()
+            .enumerate()
+            .map(|(i, read)| format!(">r{}\n{}\n", i, read))
+            .collect();
+    }
+
+    /// Unprocessed result holding a hand-built alignment
+    ///
+    /// # Arguments
+    ///
+    /// * `g_ali` - aligned genome sequence
+    /// * `q_ali` - aligned query sequence
+    ///
+    /// # Returns
+    ///
+    /// * `QumaResult` - result with every statistic zeroed
+    fn alignment(g_ali: &str, q_ali: &str) -> QumaResult {
+        return QumaResult {
+            q_ali: q_ali.to_string(),
+            g_ali: g_ali.to_string(),
+            val: String::new(),
+            perc: 0.0,
+            pconv: 0.0,
+            gap: 0,
+            menum: 0,
+            unconv: 0,
+            conv: 0,
+            quma_match: 0,
+            ali_mis: 0,
+            ali_len: 0,
+            g_start: 0,
+            q_start: 0,
+            conversion_sites: 0,
+            cpg_positions: Vec::new(),
+            score: 0,
+            conv_eff: 0.0,
+            midline: String::new(),
+            no_cpg: false,
+            mismatch_positions: Vec::new(),
+        };
+    }
+
+    /// Options keeping every well-aligned read, whatever its identity or
+    /// methylation
+    fn lenient() -> QumaBuilder {
+        return QumaBuilder {
+            pconv_threshold: 100.0,
+            percent_threshold: 100.0,
+            ..QumaBuilder::default()
+        };
+    }
+
+    #[test]
+    fn analyze_runs_the_pipeline_in_rust() {
+        let reads = format!(
+            ">read1\n{}\n>read2\n{}\n",
+            bisulfite(&genome_seq(), &[true, false, true, false]),
+            rev_comp(&bisulfite(&genome_seq(), &[true, true, true, true])),
+        );
+        let quma = Quma::analyze(GENOME, &reads).unwrap();
+
+        assert_eq!(quma.data.len(), 2);
+        assert_eq!(quma.data[0].res.val, "1010");
+        assert_eq!(quma.data[0].dir, Direction::Forward);
+        assert_eq!(quma.data[1].res.val, "1111");
+        assert_eq!(quma.data[1].dir, Direction::Reverse);
+    }
+
+    #[test]
+    fn reverse_strand_positions_follow_the_call_context() {
+        // single CHG with its cytosine at 10; its reverse-strand partner
+        // cytosine pairs with the G at 12
+        let genome = ">chg\nAATTAGTTAACAGTTATAATTATTAATTATAT\n";
+        let read = format!(">read\n{}\n", rev_comp(&parse_genome(genome)));
+        let builder = QumaBuilder {
+            contexts: vec![Context::CHG],
+            ..QumaBuilder::default()
+        };
+        let quma = builder.run(genome.to_string(), read).unwrap();
+
+        assert_eq!(quma.data[0].gdir, Direction::Reverse);
+        assert_eq!(quma.data[0].res.cpg_positions, vec![12]);
+    }
+
+    #[test]
+    fn ambiguous_cpgs_are_kept_apart_from_plain_calls() {
+        let genome = format!(">amplicon\n{}\n", genome_seq().replacen("ACGA", "AYGA", 1));
+        let read = bisulfite(&genome_seq(), &[true, false, true, false]);
+        let quma = lenient().run(genome, format!(">read\n{}\n", read)).unwrap();
+
+        assert_eq!(quma.data[0].res.val, "m010");
+        assert_eq!((quma.data[0].res.conv, quma.data[0].res.unconv), (1, 2));
+        assert_eq!(quma.calls_at(vec![8, 17, 28], None).unwrap(), vec!["m01"]);
+        let (calls, mask) = quma.binary_matrix(None).unwrap();
+        assert_eq!(mask.row(0).to_vec(), vec![0, 1, 1, 1]);
+        assert_eq!(calls.row(0).to_vec(), vec![0, 0, 1, 0]);
+        assert!(!quma.site_methylation(None).unwrap().contains_key(&8));
+    }
+
+    #[test]
+    fn pooled_site_accessors_agree() {
+        let reads = format!(
+            ">a\n{}\n>b\n{}\n>c\n{}\n",
+            bisulfite(&genome_seq(), &[true, false, true, false]),
+            bisulfite(&genome_seq(), &[true, true, true, true]),
+            bisulfite(&genome_seq(), &[false, false, false, false]),
+        );
+        let quma = lenient().run(GENOME.to_string(), reads).unwrap();
+
+        let sites = quma.site_methylation(None).unwrap();
+        let expected: BTreeMap<usize, (i32, i32)> =
+            BTreeMap::from([(8, (2, 3)), (17, (1, 3)), (28, (2, 3)), (40, (1, 3))]);
+        assert_eq!(sites, expected);
+
+        let consensus = quma.consensus_methylation(None).unwrap();
+        let discordance = quma.site_discordance(None).unwrap();
+        assert!(consensus.keys().eq(sites.keys()));
+        assert!(discordance.keys().eq(sites.keys()));
+        assert_eq!(consensus[&17], (1, 3, 1.0 / 3.0));
+        assert_eq!(discordance[&8], 1.0 / 3.0);
+        assert_eq!(
+            quma.to_bedgraph("chr1", 100, None).unwrap().lines().count(),
+            sites.len()
+        );
+    }
+
+    #[test]
+    fn pooled_accessors_keep_references_apart() {
+        let other = "GGATTCGTTAAGTCGATTGACTTAGCGTATTGAGCAATTCGTAAGTTGAC";
+        let genomes = format!("{}>other\n{}\n", GENOME, other);
+        let reads = format!(
+            ">a\n{}\n>b\n{}\n",
+            bisulfite(&genome_seq(), &[true, false, true, false]),
+            bisulfite(other, &[true, true, true, true]),
+        );
+        let quma = lenient().run(genomes, reads).unwrap();
+
+        assert_eq!(quma.data[0].genome, "amplicon");
+        assert_eq!(quma.data[1].genome, "other");
+        assert!(!quma.data[0].ambiguous_reference);
+        let expected: BTreeMap<usize, (i32, i32)> =
+            BTreeMap::from([(8, (1, 1)), (17, (0, 1)), (28, (1, 1)), (40, (0, 1))]);
+        assert_eq!(quma.site_methylation(None).unwrap(), expected);
+        let other_sites = cpg_sites(other);
+        let pooled = quma.site_methylation(Some("other")).unwrap();
+        assert!(pooled.keys().eq(other_sites.iter()));
+        assert!(pooled.values().all(|&counts| counts == (1, 1)));
+        assert_eq!(quma.calls_at(vec![8], None).unwrap(), vec!["1", "."]);
+        assert_eq!(quma.binary_matrix(Some("other")).unwrap().0.nrows(), 1);
+        assert!(quma.binary_matrix(Some("missing")).is_err());
+        assert_eq!(
+            quma.cpg_indices()[1],
+            (0..other_sites.len()).map(Some).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn call_qualities_skip_trimmed_primers() {
+        let primer = "GATTAG";
+        let read = bisulfite(&genome_seq(), &[true, false, true, false]);
+        let forward = format!("{}{}", primer, read);
+        let reverse = format!("{}{}", primer, rev_comp(&read));
+        let mut forward_qual = vec![b'I'; forward.len()];
+        let mut reverse_qual = vec![b'I'; reverse.len()];
+        // Q10 on the C of the CpG at genome position 8
+        forward_qual[primer.len() + 8] = b'+';
+        reverse_qual[primer.len() + read.len() - 1 - 8] = b'+';
+        let reads = format!(
+            "@fwd\n{}\n+\n{}\n@rev\n{}\n+\n{}\n",
+            forward,
+            String::from_utf8(forward_qual).unwrap(),
+            reverse,
+            String::from_utf8(reverse_qual).unwrap(),
+        );
+        let builder = QumaBuilder {
+            fastq: true,
+            trim_primers: Some((primer.to_string(), String::new())),
+            ..lenient()
+        };
+        let quma = builder.run(GENOME.to_string(), reads).unwrap();
+
+        assert_eq!(quma.data[1].dir, Direction::Reverse);
+        let quals: Vec<(String, String)> = quma
+            .long_table()
+            .lines()
+            .skip(1)
+            .map(|row| {
+                let fields: Vec<&str> = row.split('\t').collect();
+                (fields[3].to_string(), fields[5].to_string())
+            })
+            .collect();
+        let expected = ["10", "40", "40", "40"];
+        for read in 0..2 {
+            for (cpg, (site, qual)) in quals[read * 4..read * 4 + 4].iter().enumerate() {
+                assert_eq!(site, &cpg_sites(&genome_seq())[cpg].to_string());
+                assert_eq!(qual, expected[cpg]);
+            }
+        }
+    }
+
+    #[test]
+    fn reads_tied_between_references_are_flagged() {
+        let genomes = format!(">beta\n{}\n>alpha\n{}\n", genome_seq(), genome_seq());
+        let reads = format!(
+            ">a\n{}\n",
+            bisulfite(&genome_seq(), &[true, false, true, false])
+        );
+
+        let quma = lenient().run(genomes.clone(), reads.clone()).unwrap();
+        assert!(quma.data[0].ambiguous_reference);
+        assert_eq!(quma.data[0].genome, "alpha");
+        assert_eq!(quma.data[0].exc, 0);
+
+        let builder = QumaBuilder {
+            exclude_ambiguous: true,
+            ..lenient()
+        };
+        let quma = builder.run(genomes, reads).unwrap();
+        assert!(quma.data[0].ambiguous_reference);
+        assert_eq!(quma.data[0].reason.as_deref(), Some("ambiguous"));
+    }
+
+    #[test]
+    fn core_helpers_are_usable_without_quma() {
+        let genomes = parse_genomes(GENOME);
+        let qseq = parse_biseq(&format!(
+            ">read\n{}\n",
+            bisulfite(&genome_seq(), &[false; 4])
+        ));
+        let builder = QumaBuilder::default();
+        let data = process_fasta_output(
+            qseq,
+            String::from("queryF"),
+            String::from("queryR"),
+            &genome_fastas(&genomes),
+            &builder.contexts,
+            builder.align_options(),
+            builder.thresholds(),
+            &scoring_matrix(None).unwrap(),
+            None,
+            None,
+        )
+        .unwrap();
+
+        assert_eq!(data.len(), 1);
+        assert_eq!(data[0].res.val, "0000");
+        assert_eq!(data[0].res.unconv, 4);
+    }
+
+    #[test]
+    fn strand_bias_compares_read_orientations() {
+        let methylated = bisulfite(&genome_seq(), &[true; 4]);
+        let unmethylated = rev_comp(&bisulfite(&genome_seq(), &[false; 4]));
+        let reads = fasta(&[
+            methylated.clone(),
+            methylated,
+            unmethylated.clone(),
+            unmethylated,
+        ]);
+        let quma = lenient().run(GENOME.to_string(), reads).unwrap();
+
+        let bias = quma.strand_bias();
+        assert_eq!((bias.forward_reads, bias.reverse_reads), (2, 2));
+        assert_eq!(bias.forward_methylation, 100.0);
+        assert_eq!(bias.reverse_methylation, 0.0);
+        assert!(bias.z_score > STRAND_BIAS_Z);
+        assert!(bias.biased);
+
+        let balanced = strand_bias(&quma.data[..2]);
+        assert_eq!(balanced.z_score, 0.0);
+        assert!(!balanced.biased);
+    }
+
+    #[test]
+    fn genome_header_line_is_optional() {
+        let reads = fasta(&[bisulfite(&genome_seq(), &[true, false, true, false])]);
+        let quma = lenient().run(GENOME.to_string(), reads.clone()).unwrap();
+        let lines: Vec<&str> = quma.values.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with(&format!("genome\t0\t{}\t1\t0\t", genome_seq())));
+        assert_eq!(lines[0].split('\t').count(), lines[1].split('\t').count());
+
+        let builder = QumaBuilder {
+            genome_header: false,
+            ..lenient()
+        };
+        let quma = builder.run(GENOME.to_string(), reads).unwrap();
+        assert_eq!(quma.values.lines().count(), 1);
+        assert!(quma.values.starts_with("1\tr0\t"));
+    }
+
+    #[test]
+    fn dinucleotides_are_counted_across_gaps() {
+        let counts = dinucleotide_counts("AC-GCG");
+        assert_eq!(counts.len(), 3);
+        assert_eq!(counts["CG"], 2);
+        assert_eq!((counts["AC"], counts["GC"]), (1, 1));
+
+        let reads = fasta(&[bisulfite(&genome_seq(), &[true; 4])]);
+        let quma = lenient().run(GENOME.to_string(), reads).unwrap();
+        let counts = quma.data[0].res.dinucleotide_counts();
+        assert_eq!(counts["CG"], 4);
+        assert_eq!(counts.values().sum::<i32>(), quma.data[0].res.ali_len - 1);
+    }
+
+    #[test]
+    fn recall_methylation_switches_contexts_in_place() {
+        let seq = "TTAGCAGATTACGTTAGCTGAATTCGATTAGGATAT";
+        let reads = fasta(&[bisulfite(seq, &[true, false])]);
+        let mut quma = lenient().run(format!(">chg\n{}\n", seq), reads).unwrap();
+        let alignment = quma.data[0].res.q_ali.clone();
+        let chg = context_counts(seq)["CHG"] as usize;
+        assert_eq!(chg, 2);
+
+        quma.recall_methylation(vec![Context::CHG]);
+        assert_eq!(quma.data[0].res.q_ali, alignment);
+        assert_eq!(quma.data[0].res.val, "0".repeat(chg));
+        assert_eq!(quma.contexts, vec![Context::CHG]);
+        assert!(quma.values.contains(&"0".repeat(chg)));
+
+        quma.recall_methylation(vec![Context::CpG]);
+        assert_eq!(quma.data[0].res.val, "10");
+        assert_eq!(quma.data[0].res.cpg_positions, vec![11, 24]);
+    }
+
+    #[test]
+    fn identity_weighting_favours_closer_reads() {
+        let reads = fasta(&[
+            bisulfite(&genome_seq(), &[true; 4]),
+            bisulfite(&genome_seq(), &[false; 4]),
+        ]);
+        let mut quma = lenient().run(GENOME.to_string(), reads).unwrap();
+        quma.data[0].res.perc = 100.0;
+        quma.data[1].res.perc = 50.0;
+
+        assert_eq!(quma.weighted_methylation(false, false), 50.0);
+        let weighted = quma.weighted_methylation(true, false);
+        assert!((weighted - 200.0 / 3.0).abs() < 1e-4);
+
+        quma.data[0].exc = 1;
+        assert_eq!(quma.weighted_methylation(true, false), 0.0);
+    }
+
+    #[test]
+    fn genome_cytosine_contexts_are_counted() {
+        let counts = context_counts("CGCAGCTAC");
+        assert_eq!(counts.len(), 3);
+        assert_eq!((counts["CpG"], counts["CHG"], counts["CHH"]), (1, 1, 1));
+
+        let reads = fasta(&[bisulfite(&genome_seq(), &[true; 4])]);
+        let quma = Quma::analyze(GENOME, &reads).unwrap();
+        let counts = quma.genome_context_counts(None).unwrap();
+        assert_eq!(counts["CpG"], 4);
+        assert_eq!(counts["CHG"], 0);
+
+        let genomes = format!("{}>other\nCGCAGCTAC\n", GENOME);
+        let quma = Quma::analyze(&genomes, &reads).unwrap();
+        let counts = quma.genome_context_counts(Some("other")).unwrap();
+        assert_eq!((counts["CpG"], counts["CHG"], counts["CHH"]), (1, 1, 1));
+        assert_eq!(quma.genome_context_counts(None).unwrap()["CpG"], 4);
+        assert!(quma.genome_context_counts(Some("missing")).is_err());
+    }
+
+    #[test]
+    fn binary_matrix_has_a_row_per_included_read() {
+        let reads = fasta(&[
+            bisulfite(&genome_seq(), &[true, false, true, false]),
+            bisulfite(&genome_seq(), &[false, true, false, true]),
+        ]);
+        let mut quma = lenient().run(GENOME.to_string(), reads).unwrap();
+
+        let (calls, mask) = quma.methylation_matrix(None).unwrap();
+        assert_eq!(calls, vec![vec![1, 0, 1, 0], vec![0, 1, 0, 1]]);
+        assert_eq!(mask, vec![vec![1; 4]; 2]);
+
+        quma.data[0].exc = 1;
+        let (calls, _) = quma.methylation_matrix(None).unwrap();
+        assert_eq!(calls, vec![vec![0, 1, 0, 1]]);
+    }
+
+    #[test]
+    fn rev_comp_complements_every_iupac_code() {
+        assert_eq!(rev_comp("ACGTRYMKWSBDHVN"), "NBDHVSWMKRYACGT");
+        assert_eq!(rev_comp("acgtrymkwsbdhvn"), "nbdhvswmkryacgt");
+        assert_eq!(rev_comp("AcGu"), "aCgT");
+        assert_eq!(rev_comp("AACG-"), "-CGTT");
+
+        let bases = ALPHABET.replace(['U', 'u'], "");
+        assert_eq!(rev_comp(&rev_comp(&bases)), bases);
+        for base in bases.chars() {
+            let complement = rev_comp(&base.to_string());
+            assert_eq!(complement.len(), 1);
+            assert_eq!(
+                base.is_lowercase(),
+                complement.chars().all(char::is_lowercase)
+            );
+        }
+    }
+
+    #[test]
+    fn shifted_reads_pool_at_shared_sites() {
+        let seq = genome_seq();
+        let reads = fasta(&[
+            bisulfite(&seq[..35], &[true, true, false]),
+            bisulfite(&seq[15..], &[true, true, true]),
+        ]);
+        let quma = lenient().run(GENOME.to_string(), reads).unwrap();
+
+        assert_eq!(quma.data[0].res.cpg_positions, vec![8, 17, 28]);
+        assert_eq!(quma.data[1].res.cpg_positions, vec![17, 28, 40]);
+        let expected: BTreeMap<usize, (i32, i32)> =
+            BTreeMap::from([(8, (1, 1)), (17, (2, 2)), (28, (1, 2)), (40, (1, 1))]);
+        assert_eq!(quma.site_methylation(None).unwrap(), expected);
+    }
+
+    #[test]
+    fn rev_comp_passes_other_characters_through() {
+        assert_eq!(rev_comp("$A"), "T$");
+        assert_eq!(rev_comp("${HOME}"), "}EKOD{$");
+        assert_eq!(rev_comp("Alpha"), "tdplT");
+        assert_eq!(rev_comp(""), "");
+    }
+
+    #[test]
+    fn clip_penalty_charges_clipped_read_ends() {
+        let read = bisulfite(&genome_seq(), &[true; 4]);
+        let reads = fasta(&[format!("{}GGGGGGGGGG", read)]);
+        let score = |clip_penalty| {
+            let builder = QumaBuilder {
+                clip_penalty,
+                ..lenient()
+            };
+            let quma = builder.run(GENOME.to_string(), reads.clone()).unwrap();
+            assert_eq!(quma.data[0].res.q_ali, read);
+            return quma.data[0].res.score;
+        };
+
+        let local = score(None);
+        assert_eq!(score(Some(-5)), local - 5);
+        assert_eq!(score(Some(-100)), local - 20);
+    }
+
+    #[test]
+    fn methylation_is_called_from_aligned_columns() {
+        let options = QumaBuilder::default().align_options();
+        let res = process_alignment_matches(
+            alignment("TCGACGTCA-CGT", "TTGACGTTAACGT"),
+            &[Context::CpG],
+            options,
+        );
+
+        assert_eq!(res.val, "011");
+        assert_eq!((res.conv, res.unconv, res.menum), (2, 1, 2));
+        assert_eq!(res.cpg_positions, vec![1, 4, 9]);
+        assert_eq!(
+            (res.ali_len, res.quma_match, res.ali_mis, res.gap),
+            (13, 12, 1, 1)
+        );
+        assert_eq!(res.perc, 100.0 * 12.0 / 13.0);
+        assert_eq!(res.pconv, 100.0 * 2.0 / 3.0);
+        assert_eq!((res.conversion_sites, res.conv_eff), (1, 100.0));
+        assert!(!res.no_cpg);
+
+        let res = process_alignment_matches(alignment("TTAGA", "TTAGA"), &[Context::CpG], options);
+        assert_eq!(res.val, "-");
+        assert_eq!((res.conv, res.unconv, res.menum), (0, 0, 0));
+        assert!(res.no_cpg);
+    }
+
+    #[test]
+    fn read_distances_use_shared_sites_only() {
+        let seq = genome_seq();
+        let reads = fasta(&[
+            bisulfite(&seq, &[true, false, true, false]),
+            bisulfite(&seq, &[true; 4]),
+            bisulfite(&seq[..15], &[false]),
+            bisulfite(&seq[30..], &[false]),
+        ]);
+        let quma = lenient().run(GENOME.to_string(), reads).unwrap();
+        assert_eq!(quma.data[2].res.cpg_positions, vec![8]);
+        assert_eq!(quma.data[3].res.cpg_positions, vec![40]);
+
+        let distances = quma.methylation_distance_matrix(None).unwrap();
+        assert_eq!(distances.len(), 4);
+        assert_eq!(distances[0][0], 0.0);
+        assert_eq!((distances[0][1], distances[1][0]), (0.5, 0.5));
+        assert_eq!(distances[0][2], 1.0);
+        assert_eq!(distances[0][3], 0.0);
+        assert_eq!(distances[1][3], 1.0);
+        assert!(distances[2][3].is_nan());
+    }
+
+    #[test]
+    fn fasta_reads_may_wrap_over_lines() {
+        let reads = parse_biseq(">a first\nACGT\nacgt \n>b\nTTTT\n>empty\n");
+        assert_eq!(reads.len(), 2);
+        assert_eq!(
+            (reads[0].com.as_str(), reads[0].seq.as_str()),
+            ("a first", "ACGTACGT")
+        );
+        assert_eq!(
+            (reads[1].com.as_str(), reads[1].seq.as_str()),
+            ("b", "TTTT")
+        );
+
+        let read = bisulfite(&genome_seq(), &[true, false, true, false]);
+        let wrapped = format!(">r0\n{}\n{}\n{}\n", &read[..20], &read[20..40], &read[40..]);
+        let quma = lenient().run(GENOME.to_string(), wrapped).unwrap();
+        assert_eq!(quma.data[0].fasta.seq, read);
+        assert_eq!(quma.data[0].res.val, "1010");
+    }
+
+    #[test]
+    fn windows_line_endings_become_newlines() {
+        let scrubbed = scrub_whitespace(">a\r\nACGT\r\n\r\n>b\r\rTTAA\r\n");
+        assert_eq!(scrubbed, ">a\nACGT\n>b\nTTAA");
+        assert!(!scrubbed.contains('\\'));
+
+        let reads = parse_biseq(">a\r\nACGT\r\n\r\n>b\r\nTTAA\r\n");
+        assert_eq!(reads[0].seq, "ACGT");
+        assert_eq!(reads[1].seq, "TTAA");
+        assert_eq!(parse_genome(">g\r\nacgt\r\n\r\nTTAA\r\n"), "ACGTTTAA");
+    }
+
+    #[test]
+    fn trajectories_follow_the_given_read_order() {
+        let seq = genome_seq();
+        let reads = fasta(&[
+            bisulfite(&seq, &[true, false, true, false]),
+            bisulfite(&seq[..35], &[false, true, true]),
+        ]);
+        let quma = lenient().run(GENOME.to_string(), reads).unwrap();
+
+        let trajectory = quma.methylation_trajectory(vec![1, 0, 1], None).unwrap();
+        let second = vec![Some(0), Some(1), Some(1), None];
+        assert_eq!(trajectory[0], second);
+        assert_eq!(trajectory[1], vec![Some(1), Some(0), Some(1), Some(0)]);
+        assert_eq!(trajectory[2], second);
+        assert!(quma.methylation_trajectory(vec![2], None).is_err());
+    }
+
+    #[test]
+    fn reads_of_the_reverse_strand_align_to_it() {
+        let bottom = rev_comp(&genome_seq());
+        let reads = fasta(&[bisulfite(&bottom, &[true, true, false, false])]);
+        let quma = lenient().run(GENOME.to_string(), reads).unwrap();
+
+        assert_eq!(quma.data[0].dir, Direction::Forward);
+        assert_eq!(quma.data[0].gdir, Direction::Reverse);
+        assert_eq!(quma.data[0].res.val, "1100");
+        assert_eq!(quma.data[0].res.cpg_positions, vec![40, 28, 17, 8]);
+        assert_eq!(
+            quma.calls_at(vec![8, 17, 28, 40], None).unwrap(),
+            vec!["0011"]
+        );
+    }
+
+    #[test]
+    fn conversion_informative_cytosines_are_counted() {
+        let counts = context_counts(&genome_seq());
+        let non_cpg = counts["CHG"] + counts["CHH"];
+        let read = bisulfite(&genome_seq(), &[true; 4]);
+        let mut masked = read.clone().into_bytes();
+        // the non-CpG cytosine at genome position 26 is read as N
+        assert_eq!(&genome_seq()[26..27], "C");
+        masked[26] = b'N';
+        let reads = fasta(&[read, String::from_utf8(masked).unwrap()]);
+        let quma = lenient().run(GENOME.to_string(), reads).unwrap();
+
+        assert_eq!(quma.data[0].res.conversion_sites, non_cpg);
+        assert_eq!(quma.data[1].res.conversion_sites, non_cpg - 1);
+    }
+
+    #[test]
+    fn exclusion_thresholds_are_configurable() {
+        let read = bisulfite(&genome_seq(), &[false; 4]);
+        let reads = fasta(std::slice::from_ref(&read));
+        let quma = lenient().run(GENOME.to_string(), reads.clone()).unwrap();
+        assert_eq!((quma.data[0].res.unconv, quma.data[0].exc), (4, 0));
+
+        let builder = QumaBuilder {
+            unconv_threshold: 3,
+            ..lenient()
+        };
+        let quma = builder.run(GENOME.to_string(), reads).unwrap();
+        assert_eq!(quma.data[0].exc, 1);
+        assert_eq!(quma.data[0].reason.as_deref(), Some("unconv"));
+        assert_eq!(quma.unconv_threshold, 3);
+
+        // two substitutions away from the genome in the middle of the read
+        let mut mutated = read.into_bytes();
+        mutated[21] = b'C';
+        mutated[33] = b'C';
+        let reads = fasta(&[String::from_utf8(mutated).unwrap()]);
+        let builder = QumaBuilder {
+            mismatch_threshold: 1,
+            ..lenient()
+        };
+        let quma = builder.run(GENOME.to_string(), reads).unwrap();
+        assert_eq!(quma.data[0].res.ali_mis, 2);
+        assert_eq!(quma.data[0].reason.as_deref(), Some("mismatch"));
+    }
+
+    #[test]
+    fn calls_are_reported_at_requested_coordinates() {
+        let seq = genome_seq();
+        let mut substituted = bisulfite(&seq, &[true; 4]).into_bytes();
+        substituted[17] = b'A';
+        let reads = fasta(&[
+            bisulfite(&seq, &[true, false, true, false]),
+            String::from_utf8(substituted).unwrap(),
+            bisulfite(&seq[..35], &[false, false, true]),
+        ]);
+        let quma = lenient().run(GENOME.to_string(), reads).unwrap();
+
+        let calls = quma.calls_at(vec![40, 8, 9, 17, 100], None).unwrap();
+        assert_eq!(calls, vec!["01.0.", "11.A.", ".0.0."]);
+        assert_eq!(quma.calls_at(Vec::new(), None).unwrap(), vec![""; 3]);
+    }
+
+    #[test]
+    fn quma_result_fields_are_readable_from_python() {
+        let options = QumaBuilder::default().align_options();
+        let res = process_alignment_matches(alignment("TCGA", "TTGA"), &[Context::CpG], options);
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let res = Py::new(py, res).unwrap();
+            let get = |name: &str| res.getattr(py, name).unwrap();
+            let fields = [
+                "q_ali",
+                "g_ali",
+                "val",
+                "perc",
+                "pconv",
+                "gap",
+                "menum",
+                "unconv",
+                "conv",
+                "quma_match",
+                "ali_mis",
+                "ali_len",
+                "g_start",
+                "q_start",
+                "conversion_sites",
+                "cpg_positions",
+                "score",
+                "conv_eff",
+                "midline",
+                "no_cpg",
+                "mismatch_positions",
+            ];
+            for field in fields {
+                get(field);
+            }
+            assert_eq!(get("val").extract::<String>(py).unwrap(), "0");
+            assert_eq!(get("unconv").extract::<i32>(py).unwrap(), 1);
+            assert_eq!(get("perc").extract::<f32>(py).unwrap(), 100.0);
+            assert_eq!(
+                get("cpg_positions").extract::<Vec<i32>>(py).unwrap(),
+                vec![1]
+            );
+
+            res.setattr(py, "val", "1").unwrap();
+            assert_eq!(res.borrow(py).val, "1");
+        });
+    }
+
+    #[test]
+    fn unconverted_methylated_reads_are_suspect() {
+        let reads = fasta(&[
+            genome_seq(),
+            bisulfite(&genome_seq(), &[true; 4]),
+            bisulfite(&genome_seq(), &[false; 4]),
+        ]);
+        let quma = lenient().run(GENOME.to_string(), reads).unwrap();
+
+        assert_eq!(quma.data[0].res.pconv, 100.0);
+        assert_eq!(quma.data[0].res.conv_eff, 0.0);
+        assert!(quma.data[0].res.conversion_suspect());
+        assert_eq!(quma.data[1].res.pconv, 100.0);
+        assert!(!quma.data[1].res.conversion_suspect());
+        assert_eq!(quma.suspect_reads(), vec![0]);
+    }
+
+    #[test]
+    fn fasta_fields_are_readable_from_python() {
+        let fa = parse_biseq_fastq("@read1\nACGT\n+\nII#I\n").remove(0);
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let fa = Py::new(py, fa).unwrap();
+            let get = |name: &str| fa.getattr(py, name).unwrap();
+            assert_eq!(get("com").extract::<String>(py).unwrap(), "read1");
+            assert_eq!(get("pos").extract::<String>(py).unwrap(), "");
+            assert_eq!(get("seq").extract::<String>(py).unwrap(), "ACGT");
+            let qual = get("qual").extract::<Option<String>>(py).unwrap();
+            assert_eq!(qual.as_deref(), Some("II#I"));
+            assert_eq!(get("trim_start").extract::<usize>(py).unwrap(), 0);
+            assert_eq!(get("trim_end").extract::<usize>(py).unwrap(), 0);
+
+            fa.setattr(py, "com", "renamed").unwrap();
+            assert_eq!(fa.borrow(py).com, "renamed");
+        });
+    }
+
+    #[test]
+    fn reference_fields_are_readable_from_python() {
+        let reads = fasta(&[rev_comp(&bisulfite(
+            &genome_seq(),
+            &[true, false, true, false],
+        ))]);
+        let quma = lenient().run(GENOME.to_string(), reads).unwrap();
+        let reference = quma.data[0].clone();
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let reference = Py::new(py, reference).unwrap();
+            let get = |name: &str| reference.getattr(py, name).unwrap();
+            assert_eq!(get("fasta").extract::<Fasta>(py).unwrap().com, "r0");
+            assert_eq!(get("res").extract::<QumaResult>(py).unwrap().val, "1010");
+            assert_eq!(
+                get("dir").extract::<Direction>(py).unwrap(),
+                Direction::Reverse
+            );
+            assert_eq!(
+                get("gdir").extract::<Direction>(py).unwrap(),
+                Direction::Forward
+            );
+            assert_eq!(get("exc").extract::<i32>(py).unwrap(), 0);
+            assert_eq!(get("reason").extract::<Option<String>>(py).unwrap(), None);
+            assert_eq!(get("genome").extract::<String>(py).unwrap(), "amplicon");
+
+            reference.setattr(py, "exc", 1).unwrap();
+            assert_eq!(reference.borrow(py).exc, 1);
+        });
+    }
+
+    #[test]
+    fn ambiguous_read_bases_match_when_configured() {
+        assert_eq!(count_matches(b"ARNY", b"AGCA", false, true), 3);
+        assert_eq!(count_matches(b"ARNY", b"AGCA", false, false), 1);
+
+        let mut read = bisulfite(&genome_seq(), &[true; 4]).into_bytes();
+        read[3] = b'R';
+        let reads = fasta(&[String::from_utf8(read).unwrap()]);
+        let quma = lenient().run(GENOME.to_string(), reads.clone()).unwrap();
+        assert_eq!(quma.data[0].res.ali_mis, 0);
+
+        let builder = QumaBuilder {
+            iupac_match: false,
+            ..lenient()
+        };
+        let quma = builder.run(GENOME.to_string(), reads).unwrap();
+        assert_eq!(quma.data[0].res.ali_mis, 1);
+        assert_eq!(quma.data[0].res.mismatch_positions, vec![3]);
+    }
+
+    #[test]
+    fn json_export_holds_the_genome_and_reads() {
+        let reads = fasta(&[bisulfite(&genome_seq(), &[true, false, true, false])]);
+        let quma = lenient().run(GENOME.to_string(), reads).unwrap();
+
+        let json: serde_json::Value = serde_json::from_str(&quma.get_json().unwrap()).unwrap();
+        assert_eq!(json["gseq"], genome_seq());
+        assert_eq!(json["data"].as_array().unwrap().len(), 1);
+        assert_eq!(json["data"][0]["fasta"]["com"], "r0");
+        assert_eq!(json["data"][0]["res"]["val"], "1010");
+        assert_eq!(json["data"][0]["dir"], "Forward");
+
+        let data: Vec<Reference> = serde_json::from_value(json["data"].clone()).unwrap();
+        assert!(data[0].res == quma.data[0].res);
+    }
+
+    #[test]
+    fn substitution_spectrum_counts_base_pairs() {
+        let res = alignment("ACGTN-C", "ATGAAAT");
+        assert_eq!(
+            res.substitution_matrix(),
+            vec![
+                vec![1, 0, 0, 0],
+                vec![0, 0, 0, 2],
+                vec![0, 0, 1, 0],
+                vec![1, 0, 0, 0],
+            ]
+        );
+    }
+
+    #[test]
+    fn fastq_records_keep_their_qualities() {
+        let reads = parse_biseq_fastq("@a\nacgt\n+\n@II#\n@b\nTTGA\n+a\nIIII\n@c\nACGT\n+\n");
+        assert_eq!(reads.len(), 2);
+        assert_eq!(
+            (reads[0].com.as_str(), reads[0].seq.as_str()),
+            ("a", "ACGT")
+        );
+        assert_eq!(reads[0].qual.as_deref(), Some("@II#"));
+        assert_eq!(
+            (reads[1].com.as_str(), reads[1].seq.as_str()),
+            ("b", "TTGA")
+        );
+
+        let read = bisulfite(&genome_seq(), &[true, false, true, false]);
+        let reads = format!("@r0\n{}\n+\n{}\n", read, "I".repeat(read.len()));
+        let builder = QumaBuilder {
+            fastq: true,
+            ..lenient()
+        };
+        let quma = builder.run(GENOME.to_string(), reads).unwrap();
+        assert_eq!(quma.data[0].res.val, "1010");
+        assert_eq!(quma.data[0].fasta.qual.as_ref().unwrap().len(), read.len());
+    }
+
+    #[test]
+    fn orientations_below_the_minimum_score_are_dropped() {
+        let reads = fasta(&[bisulfite(&genome_seq(), &[true; 4])]);
+        let quma = lenient().run(GENOME.to_string(), reads.clone()).unwrap();
+        let score = quma.data[0].res.score;
+
+        let with_minimum = |min_score| {
+            let builder = QumaBuilder {
+                min_score: Some(min_score),
+                ..lenient()
+            };
+            return builder.run(GENOME.to_string(), reads.clone()).unwrap();
+        };
+        let quma = with_minimum(score);
+        assert_eq!((quma.data[0].exc, quma.data[0].res.score), (0, score));
+        let quma = with_minimum(score + 1);
+        assert_eq!(quma.data[0].exc, 1);
+        assert_eq!(quma.data[0].reason.as_deref(), Some("unaligned"));
+    }
+
+    #[test]
+    fn bedgraph_reports_the_level_of_each_site() {
+        let reads = fasta(&[
+            bisulfite(&genome_seq(), &[true, false, true, false]),
+            bisulfite(&genome_seq(), &[true; 4]),
+        ]);
+        let quma = lenient().run(GENOME.to_string(), reads).unwrap();
+
+        assert_eq!(
+            quma.to_bedgraph("chr1", 0, None).unwrap(),
+            "chr1\t8\t9\t1\nchr1\t17\t18\t0.5\nchr1\t28\t29\t1\nchr1\t40\t41\t0.5\n"
+        );
+    }
+
+    #[test]
+    fn gzip_inputs_are_decompressed() {
+        let gzip = |text: &str| {
+            let mut encoder =
+                flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+            std::io::Write::write_all(&mut encoder, text.as_bytes()).unwrap();
+            return encoder.finish().unwrap();
+        };
+        let reads = fasta(&[bisulfite(&genome_seq(), &[true, false, true, false])]);
+
+        // concatenated members decode as one stream
+        let mut members = gzip(&reads[..10]);
+        members.extend(gzip(&reads[10..]));
+        assert_eq!(decode_input(&members).unwrap(), reads);
+        assert_eq!(decode_input(reads.as_bytes()).unwrap(), reads);
+        assert!(decode_input(&[0xff, 0xfe]).is_err());
+
+        let quma = Quma::from_gzip(&gzip(GENOME), &members, None).unwrap();
+        assert_eq!(quma.gseq, genome_seq());
+        assert_eq!(quma.data[0].res.val, "1010");
+        assert_eq!(
+            quma.percent_threshold,
+            QumaBuilder::default().percent_threshold
+        );
+
+        let options = QumaBuilder {
+            contexts: vec![Context::CpG, Context::CHH],
+            min_ali_len: 60,
+            ..lenient()
+        };
+        let quma = Quma::from_gzip(&gzip(GENOME), &members, Some(options)).unwrap();
+        assert_eq!(quma.contexts, vec![Context::CpG, Context::CHH]);
+        assert_eq!(quma.data[0].reason.as_deref(), Some("ali_len"));
+    }
+
+    #[test]
+    fn stored_alignments_can_be_rescored() {
+        let reads = fasta(&[bisulfite(&genome_seq(), &[false; 4])]);
+        let mut quma = lenient().run(GENOME.to_string(), reads).unwrap();
+        let before = quma.data[0].res.clone();
+        let converted = genome_seq().matches('C').count() as i32;
+        assert_eq!(before.ali_mis, 0);
+
+        quma.rescore_matches(false, true);
+        let after = &quma.data[0].res;
+        assert_eq!(after.ali_mis, converted);
+        assert_eq!(after.quma_match, before.ali_len - converted);
+        assert_eq!(
+            after.perc,
+            100.0 * after.quma_match as f32 / after.ali_len as f32
+        );
+        assert_eq!(
+            (after.q_ali.as_str(), after.val.as_str()),
+            (before.q_ali.as_str(), "0000")
+        );
+        assert!(!quma.bisulfite_match);
+
+        quma.rescore_matches(true, true);
+        assert!(quma.data[0].res == before);
+    }
+
+    #[test]
+    fn bad_inputs_are_errors_not_panics() {
+        let reads = fasta(&[bisulfite(&genome_seq(), &[true; 4])]);
+        assert!(matches!(
+            Quma::analyze("", &reads),
+            Err(QumaError::EmptyGenome)
+        ));
+        assert!(matches!(
+            Quma::analyze(">empty\n", &reads),
+            Err(QumaError::EmptyGenome)
+        ));
+        assert!(matches!(
+            Quma::analyze(GENOME, ""),
+            Err(QumaError::EmptyQuery)
+        ));
+        match Quma::analyze(GENOME, ">bad\nACG#T\n") {
+            Err(QumaError::UnexpectedCharacter('#', name)) => assert_eq!(name, "bad"),
+            _ => panic!("expected an unexpected character error"),
+        }
+
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let err = PyErr::from(QumaError::EmptyQuery);
+            assert!(err.is_instance_of::<PyValueError>(py));
+            assert_eq!(err.value(py).to_string(), "query file contains no reads");
+        });
+    }
+
+    #[test]
+    fn cpg_spacing_follows_genome_order() {
+        let seq = genome_seq();
+        let reads = fasta(&[
+            bisulfite(&seq, &[true, false, true, false]),
+            rev_comp(&bisulfite(&seq[15..], &[true; 3])),
+            bisulfite(&seq[..15], &[true]),
+        ]);
+        let quma = lenient().run(GENOME.to_string(), reads).unwrap();
+
+        assert_eq!(quma.data[1].dir, Direction::Reverse);
+        let spacing = quma.cpg_spacing();
+        assert_eq!(spacing[0], vec![9, 11, 12]);
+        assert_eq!(spacing[1], vec![11, 12]);
+        assert!(spacing[2].is_empty());
+    }
+
+    #[test]
+    fn bisulfite_scoring_rewards_converted_cytosines() {
+        let matrix = scoring_matrix(None).unwrap();
+        assert_eq!(
+            bisulfite_score(&matrix, b'C', b'T'),
+            quma_score(&matrix, b'C', b'C')
+        );
+        assert_eq!(
+            bisulfite_score(&matrix, b'T', b'C'),
+            quma_score(&matrix, b'T', b'C')
+        );
+        assert_eq!(
+            bisulfite_score(&matrix, b'G', b'A'),
+            quma_score(&matrix, b'G', b'A')
+        );
+        assert!(quma_score(&matrix, b'C', b'T') < quma_score(&matrix, b'C', b'C'));
+
+        let reads = fasta(&[bisulfite(&genome_seq(), &[false; 4])]);
+        let plain = lenient().run(GENOME.to_string(), reads.clone()).unwrap();
+        let builder = QumaBuilder {
+            bisulfite_scoring: true,
+            ..lenient()
+        };
+        let scored = builder.run(GENOME.to_string(), reads).unwrap();
+        let perfect = genome_seq().len() as i32 * quma_score(&matrix, b'A', b'A');
+        assert_eq!(scored.data[0].res.score, perfect);
+        assert!(plain.data[0].res.score < perfect);
+    }
+
+    #[test]
+    fn reads_off_the_expected_length_are_outliers() {
+        let thresholds = QumaBuilder {
+            expected_length: Some(100),
+            length_tolerance: 10.0,
+            ..QumaBuilder::default()
+        }
+        .thresholds();
+        assert!(!length_outlier(110, thresholds));
+        assert!(!length_outlier(90, thresholds));
+        assert!(length_outlier(111, thresholds));
+        assert!(length_outlier(89, thresholds));
+        assert!(!length_outlier(10, QumaBuilder::default().thresholds()));
+
+        let seq = genome_seq();
+        let reads = fasta(&[
+            bisulfite(&seq, &[true; 4]),
+            bisulfite(&seq[..35], &[true; 3]),
+        ]);
+        let builder = QumaBuilder {
+            expected_length: Some(seq.len()),
+            ..lenient()
+        };
+        let quma = builder.run(GENOME.to_string(), reads).unwrap();
+        assert_eq!(quma.data[0].exc, 0);
+        assert_eq!(quma.data[1].reason.as_deref(), Some("length"));
+    }
+
+    #[test]
+    fn parallel_alignment_keeps_input_order() {
+        let patterns: Vec<String> = (0..16)
+            .map(|i| {
+                let methylated: Vec<bool> = (0..4).map(|bit| i >> bit & 1 == 1).collect();
+                bisulfite(&genome_seq(), &methylated)
+            })
+            .collect();
+        let reads = fasta(&patterns);
+        let run = |num_threads| {
+            let builder = QumaBuilder {
+                num_threads,
+                ..lenient()
+            };
+            return builder.run(GENOME.to_string(), reads.clone()).unwrap();
+        };
+
+        let serial = run(Some(1));
+        let parallel = run(Some(4));
+        assert_eq!(serial.values, parallel.values);
+        for (i, reference) in parallel.data.iter().enumerate() {
+            assert_eq!(reference.fasta.com, format!("r{}", i));
+            let expected: String = (0..4)
+                .map(|bit| if i >> bit & 1 == 1 { '1' } else { '0' })
+                .collect();
+            assert_eq!(reference.res.val, expected);
+        }
+    }
+
+    #[test]
+    fn long_table_reports_call_qualities() {
+        let read = bisulfite(&genome_seq(), &[true, false, true, false]);
+        let mut qual = vec![b'I'; read.len()];
+        for (pos, q) in [(8, b'5'), (17, b'?'), (28, b'+')] {
+            qual[pos] = q;
+        }
+        let reads = format!("@r0\n{}\n+\n{}\n", read, String::from_utf8(qual).unwrap());
+        let builder = QumaBuilder {
+            fastq: true,
+            ..lenient()
+        };
+        let quma = builder.run(GENOME.to_string(), reads).unwrap();
+        assert_eq!(
+            quma.long_table(),
+            "pos\tread\tgenome\tsite\tcall\tqual\n\
+             1\tr0\tamplicon\t8\t1\t20\n\
+             1\tr0\tamplicon\t17\t0\t30\n\
+             1\tr0\tamplicon\t28\t1\t10\n\
+             1\tr0\tamplicon\t40\t0\t40\n"
+        );
+
+        let quma = lenient().run(GENOME.to_string(), fasta(&[read])).unwrap();
+        assert!(quma
+            .long_table()
+            .lines()
+            .skip(1)
+            .all(|row| row.ends_with("\t.")));
+    }
+
+    #[test]
+    fn lookup_indexes_the_matrix_alphabet() {
+        for (i, &base) in MATRIX_ALPHABET.iter().enumerate() {
+            assert_eq!(lookup(base), Some(i));
+        }
+        assert_eq!(lookup(b'N'), Some(N_INDEX));
+        let known = (0..=255u8).filter(|&x| lookup(x).is_some()).count();
+        assert_eq!(known, 2 * MATRIX_SIZE);
+        assert_eq!(lookup(b'-'), None);
+        assert_eq!(lookup(b'X'), None);
+    }
+
+    #[test]
+    fn discordance_is_the_minority_call_fraction() {
+        let reads = fasta(&[
+            bisulfite(&genome_seq(), &[true; 4]),
+            bisulfite(&genome_seq(), &[true; 4]),
+            bisulfite(&genome_seq(), &[true, false, true, false]),
+            bisulfite(&genome_seq(), &[false, false, true, false]),
+        ]);
+        let quma = lenient().run(GENOME.to_string(), reads).unwrap();
+
+        let discordance = quma.site_discordance(None).unwrap();
+        let expected: BTreeMap<usize, f32> =
+            BTreeMap::from([(8, 0.25), (17, 0.5), (28, 0.0), (40, 0.5)]);
+        assert_eq!(discordance, expected);
+    }
+
+    #[test]
+    fn gap_penalties_are_configurable() {
+        let read = bisulfite(&genome_seq(), &[true; 4]);
+        // three bases missing between the second and third CpG
+        let reads = fasta(&[format!("{}{}", &read[..22], &read[25..])]);
+        let score = |gap_open, gap_extend| {
+            let builder = QumaBuilder {
+                gap_open,
+                gap_extend,
+                ..lenient()
+            };
+            let quma = builder.run(GENOME.to_string(), reads.clone()).unwrap();
+            assert_eq!(quma.data[0].res.gap, 3);
+            assert_eq!(quma.data[0].res.val, "1111");
+            return quma.data[0].res.score;
+        };
+
+        let default = score(-10, -1);
+        assert_eq!(score(-4, -2), default + 13 - 10);
+        assert_eq!(score(-10, -3), default - 6);
+    }
+
+    #[test]
+    fn non_cpg_contexts_can_be_called() {
+        let seq = b"CGCAGCTACN";
+        let contexts: Vec<Option<Context>> =
+            (0..seq.len()).map(|i| cytosine_context(seq, i)).collect();
+        assert_eq!(contexts[0], Some(Context::CpG));
+        assert_eq!(contexts[2], Some(Context::CHG));
+        assert_eq!(contexts[5], Some(Context::CHH));
+        assert_eq!(contexts[8], None);
+        assert_eq!(contexts.iter().flatten().count(), 3);
+
+        let seq = "TTAGCAGATTACGTTAGCTAAATTCGATTAGGATAT";
+        let genome = format!(">contexts\n{}\n", seq);
+        let reads = fasta(&[bisulfite(seq, &[true, false])]);
+        let builder = QumaBuilder {
+            contexts: vec![Context::CpG, Context::CHG, Context::CHH],
+            ..lenient()
+        };
+        let quma = builder.run(genome.clone(), reads.clone()).unwrap();
+        assert_eq!(quma.data[0].res.val, "0100");
+        assert_eq!(quma.data[0].res.cpg_positions, vec![4, 11, 17, 24]);
+
+        let builder = QumaBuilder {
+            contexts: vec![Context::CHH],
+            ..lenient()
+        };
+        let quma = builder.run(genome, reads).unwrap();
+        assert_eq!(quma.data[0].res.val, "0");
+        assert_eq!(quma.data[0].res.cpg_positions, vec![17]);
+    }
+
+    #[test]
+    fn strand_balancing_keeps_the_longest_alignments() {
+        let seq = genome_seq();
+        let reads = fasta(&[
+            bisulfite(&seq[..35], &[true; 3]),
+            bisulfite(&seq, &[false; 4]),
+            bisulfite(&seq[15..], &[true; 3]),
+            rev_comp(&bisulfite(&seq, &[true; 4])),
+        ]);
+        let quma = lenient().run(GENOME.to_string(), reads).unwrap();
+
+        let balanced = balanced_reads(&quma.data);
+        let names: Vec<&str> = balanced.iter().map(|x| x.fasta.com.as_str()).collect();
+        assert_eq!(names, vec!["r1", "r3"]);
+        assert_eq!(quma.weighted_methylation(false, true), 50.0);
+        assert_eq!(quma.weighted_methylation(false, false), 100.0 * 10.0 / 14.0);
+        assert_eq!(balanced_reads(&quma.data[..3]).len(), 3);
+    }
+
+    #[test]
+    fn calls_map_to_genome_cpg_indices() {
+        let seq = genome_seq();
+        let reads = fasta(&[
+            bisulfite(&seq[15..], &[true, false, true]),
+            bisulfite(&rev_comp(&seq), &[true; 4]),
+        ]);
+        let quma = lenient().run(GENOME.to_string(), reads).unwrap();
+
+        let indices = quma.cpg_indices();
+        assert_eq!(indices[0], vec![Some(1), Some(2), Some(3)]);
+        assert_eq!(indices[1], vec![Some(3), Some(2), Some(1), Some(0)]);
+        for (reference, indices) in quma.data.iter().zip(&indices) {
+            assert_eq!(reference.res.val.len(), indices.len());
+        }
+
+        let builder = QumaBuilder {
+            contexts: vec![Context::CpG, Context::CHH],
+            ..lenient()
+        };
+        let quma = builder.run(GENOME.to_string(), fasta(&[seq])).unwrap();
+        let indices = &quma.cpg_indices()[0];
+        assert_eq!(indices.len(), quma.data[0].res.val.len());
+        let cpgs: Vec<usize> = indices.iter().flatten().copied().collect();
+        assert_eq!(cpgs, vec![0, 1, 2, 3]);
+        assert!(indices.iter().any(|x| x.is_none()));
+    }
+
+    #[test]
+    fn epiallele_table_is_sorted_by_frequency() {
+        let reads = fasta(&[
+            bisulfite(&genome_seq(), &[true, false, true, false]),
+            bisulfite(&genome_seq(), &[true; 4]),
+            bisulfite(&genome_seq(), &[true, false, true, false]),
+            bisulfite(&genome_seq(), &[false; 4]),
+        ]);
+        let mut quma = lenient().run(GENOME.to_string(), reads).unwrap();
+        quma.data[3].exc = 1;
+
+        assert_eq!(
+            quma.epialleles_tsv(),
+            "pattern\tcount\tfrequency\n\
+             1010\t2\t0.6666667\n\
+             1111\t1\t0.33333334\n"
+        );
+    }
+
+    #[test]
+    fn alignment_modes_differ_in_end_handling() {
+        let seq = genome_seq();
+        let read = format!("CGCAA{}", bisulfite(&seq[10..40], &[true; 2]));
+        let reads = fasta(std::slice::from_ref(&read));
+        let run = |align_mode| {
+            let builder = QumaBuilder {
+                align_mode,
+                ..lenient()
+            };
+            let quma = builder.run(GENOME.to_string(), reads.clone()).unwrap();
+            return quma.data[0].res.clone();
+        };
+        let aligned = |res: &QumaResult| res.q_ali.bytes().filter(|&x| x != b'-').count();
+
+        let local = run(AlignMode::Local);
+        assert_eq!((local.q_start, local.g_start), (5, 10));
+        assert_eq!(aligned(&local), 30);
+        assert_eq!(local.val, "11");
+
+        let semiglobal = run(AlignMode::Semiglobal);
+        assert_eq!(semiglobal.q_start, 0);
+        assert_eq!(aligned(&semiglobal), read.len());
+        assert!(semiglobal.score < local.score);
+
+        let global = run(AlignMode::Global);
+        assert_eq!(aligned(&global), read.len());
+        assert!(global.score < semiglobal.score);
+    }
+
+    #[test]
+    fn python_read_filters_exclude_reads() {
+        let reads = fasta(&[
+            bisulfite(&genome_seq(), &[true, false, true, false]),
+            bisulfite(&genome_seq(), &[true; 4]),
+        ]);
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let read_filter = py
+                .eval("lambda read: read.res.val == '1010'", None, None)
+                .unwrap();
+            let kwargs = pyo3::types::PyDict::new(py);
+            kwargs.set_item("pconv_threshold", 100.0).unwrap();
+            kwargs.set_item("percent_threshold", 100.0).unwrap();
+            kwargs.set_item("read_filter", read_filter).unwrap();
+            let quma = py
+                .get_type::<Quma>()
+                .call((GENOME, reads), Some(kwargs))
+                .unwrap();
+            let quma: PyRef<Quma> = quma.extract().unwrap();
+            assert_eq!((quma.data[0].exc, quma.data[1].exc), (0, 1));
+            assert_eq!(quma.data[1].reason.as_deref(), Some("filter"));
+
+            let failing = py.eval("lambda read: 1 / 0", None, None).unwrap();
+            kwargs.set_item("read_filter", failing).unwrap();
+            let reads = fasta(&[bisulfite(&genome_seq(), &[true; 4])]);
+            let err = py
+                .get_type::<Quma>()
+                .call((GENOME, reads), Some(kwargs))
+                .unwrap_err();
+            assert!(err.is_instance_of::<pyo3::exceptions::PyZeroDivisionError>(py));
+        });
+    }
+
+    #[test]
+    fn conversion_efficiency_has_a_wilson_interval() {
+        let converted = bisulfite(&genome_seq(), &[true; 4]);
+        let quma = lenient()
+            .run(GENOME.to_string(), fasta(std::slice::from_ref(&converted)))
+            .unwrap();
+        let n = quma.data[0].res.conversion_sites as f32;
+        let z2 = CONVERSION_CI_Z * CONVERSION_CI_Z;
+        let (rate, lower, upper) = quma.conversion_efficiency();
+        assert_eq!((rate, upper), (100.0, 100.0));
+        assert!((lower - 100.0 * n / (n + z2)).abs() < 1e-3);
+
+        let reads = fasta(&[converted, genome_seq()]);
+        let quma = lenient().run(GENOME.to_string(), reads).unwrap();
+        let (rate, lower, upper) = quma.conversion_efficiency();
+        assert_eq!(rate, 50.0);
+        assert!((50.0 - lower - (upper - 50.0)).abs() < 1e-3);
+        assert!(lower > 0.0 && upper < 100.0);
+
+        assert_eq!(conversion_efficiency(&[]), (0.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn best_alignment_is_chosen_by_quality() {
+        let candidate = |perc, ali_len, ali_mis, score, dir, gdir| {
+            let mut res = alignment("", "");
+            res.perc = perc;
+            res.ali_len = ali_len;
+            res.ali_mis = ali_mis;
+            res.score = score;
+            return (res, dir, gdir);
+        };
+        let (fwd, rev) = (Direction::Forward, Direction::Reverse);
+
+        let longer = candidate(80.0, 50, 10, 100, fwd, fwd);
+        let closer = candidate(95.0, 30, 2, 90, rev, rev);
+        assert_eq!(find_best_dataset(&[longer.clone(), closer.clone()]), 1);
+        assert_eq!(find_best_dataset(&[closer, longer]), 0);
+
+        let base = candidate(90.0, 40, 4, 100, rev, rev);
+        let longer = candidate(90.0, 41, 4, 100, rev, rev);
+        let fewer_mismatches = candidate(90.0, 40, 3, 100, rev, rev);
+        let higher_score = candidate(90.0, 40, 4, 101, rev, rev);
+        let forward_read = candidate(90.0, 40, 4, 100, fwd, rev);
+        let forward_genome = candidate(90.0, 40, 4, 100, rev, fwd);
+        for better in [
+            longer,
+            fewer_mismatches,
+            higher_score,
+            forward_read,
+            forward_genome,
+        ] {
+            assert_eq!(find_best_dataset(&[base.clone(), better.clone()]), 1);
+            assert_eq!(find_best_dataset(&[better, base.clone()]), 0);
+        }
+        assert_eq!(find_best_dataset(&[base.clone(), base]), 0);
+    }
+
+    #[test]
+    fn lowercase_genome_bases_mark_islands() {
+        let seq = genome_seq();
+        let genome = format!(
+            ">amplicon\n{}{}{}\n",
+            &seq[..15],
+            seq[15..30].to_lowercase(),
+            &seq[30..]
+        );
+        let reads = fasta(&[
+            bisulfite(&seq, &[true; 4]),
+            rev_comp(&bisulfite(&seq, &[true; 4])),
+        ]);
+        let builder = QumaBuilder {
+            lowercase_islands: true,
+            ..lenient()
+        };
+        let quma = builder.run(genome.clone(), reads.clone()).unwrap();
+        assert_eq!(quma.gseq, seq);
+        assert_eq!(quma.islands[0].iter().filter(|&&x| x).count(), 15);
+        let calls = quma.island_calls();
+        assert_eq!(calls[0], vec![false, true, true, false]);
+        assert_eq!(calls[1], vec![false, true, true, false]);
+
+        let quma = lenient().run(genome.clone(), reads).unwrap();
+        assert!(quma.island_calls().iter().flatten().all(|&x| !x));
+
+        // islands are annotated on each reference, and follow every context
+        let other = "ttagcgaattcaggcgtacaatccgtta";
+        let genomes = format!("{}>other\n{}\n", genome, other);
+        let builder = QumaBuilder {
+            lowercase_islands: true,
+            contexts: vec![Context::CpG, Context::CHH],
+            ..lenient()
+        };
+        let read = bisulfite(&other.to_uppercase(), &[true; 3]);
+        let quma = builder.run(genomes, fasta(&[read])).unwrap();
+        assert_eq!(quma.islands.len(), 2);
+        assert_eq!(quma.data[0].genome, "other");
+        let calls = &quma.island_calls()[0];
+        assert_eq!(calls.len(), quma.data[0].res.val.len());
+        assert!(calls.len() > 3 && calls.iter().all(|&x| x));
+    }
+
+    #[test]
+    fn empty_inputs_are_rejected() {
+        let builder = QumaBuilder::default();
+        let options = builder.align_options();
+        let mut aligner = read_aligner(options, &scoring_matrix(None).unwrap());
+        let read = fasta_make("ACGT", "read");
+        let genome = fasta_make("ACGT", "genome");
+        let empty = fasta_make("", "empty");
+        let align = |qfile: &str, gfile: &str, aligner: &mut ReadAligner| {
+            return align_seq_and_generate_stats(qfile, gfile, &builder.contexts, options, aligner);
+        };
+        assert!(matches!(
+            align(&read, &empty, &mut aligner),
+            Err(QumaError::EmptyGenome)
+        ));
+        assert!(matches!(
+            align(&empty, &genome, &mut aligner),
+            Err(QumaError::EmptyRead)
+        ));
+        assert!(align(&read, &genome, &mut aligner).is_ok());
+
+        let reads = fasta(&[bisulfite(&genome_seq(), &[true; 4])]);
+        assert!(matches!(
+            Quma::analyze(" \r\n\n", &reads),
+            Err(QumaError::EmptyGenome)
+        ));
+        assert!(matches!(
+            Quma::analyze(GENOME, ">a\n>b\n"),
+            Err(QumaError::EmptyQuery)
+        ));
+
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let err = py.get_type::<Quma>().call1(("", reads)).unwrap_err();
+            assert!(err.is_instance_of::<PyValueError>(py));
+        });
+    }
+
+    #[test]
+    fn exclusions_are_counted_by_reason() {
+        let seq = genome_seq();
+        let reads = fasta(&[
+            bisulfite(&seq, &[false; 4]),
+            bisulfite(&seq, &[true, false, true, false]),
+            bisulfite(&seq[..20], &[true, true]),
+            bisulfite(&seq, &[false; 4]),
+        ]);
+        let builder = QumaBuilder {
+            unconv_threshold: 3,
+            min_ali_len: 30,
+            ..lenient()
+        };
+        let quma = builder.run(GENOME.to_string(), reads).unwrap();
+
+        let summary = quma.exclusion_summary();
+        assert_eq!(summary.len(), 2);
+        assert_eq!((summary["unconv"], summary["ali_len"]), (2, 1));
+        assert!(lenient()
+            .run(GENOME.to_string(), fasta(&[seq]))
+            .unwrap()
+            .exclusion_summary()
+            .is_empty());
+    }
+
+    #[test]
+    fn summary_pools_included_reads() {
+        let reads = fasta(&[
+            bisulfite(&genome_seq(), &[true, false, true, false]),
+            bisulfite(&genome_seq(), &[true; 4]),
+            bisulfite(&genome_seq(), &[false; 4]),
+        ]);
+        let mut quma = lenient().run(GENOME.to_string(), reads).unwrap();
+        quma.data[2].exc = 1;
+
+        let summary = quma.summary();
+        assert_eq!(
+            (
+                summary.total_reads,
+                summary.included_reads,
+                summary.excluded_reads
+            ),
+            (3, 2, 1)
+        );
+        assert_eq!((summary.cpgs_observed, summary.methylated), (8, 6));
+        assert_eq!(summary.methylation, 75.0);
+    }
+
+    #[test]
+    fn bedgraph_shifts_sites_by_the_offset() {
+        let seq = "TTAGCAGATTACGTTAGCTAAATTCGATTAGGATAT";
+        let builder = QumaBuilder {
+            contexts: vec![Context::CpG, Context::CHH],
+            ..lenient()
+        };
+        let reads = fasta(&[bisulfite(seq, &[true, false])]);
+        let quma = builder.run(format!(">contexts\n{}\n", seq), reads).unwrap();
+
+        // the CHH call at 17 stays out of the CpG track
+        assert_eq!(quma.data[0].res.cpg_positions, vec![11, 17, 24]);
+        assert_eq!(
+            quma.to_bedgraph("chr7", 1000, None).unwrap(),
+            "chr7\t1011\t1012\t1\nchr7\t1024\t1025\t0\n"
+        );
+    }
+
+    #[test]
+    fn trimmed_positions_map_back_to_the_read() {
+        let seq = genome_seq();
+        let insert = bisulfite(&seq[5..45], &[true; 4]);
+        let reads = fasta(&[format!("GATTAG{}{}", insert, rev_comp("CCTAAC"))]);
+        let builder = QumaBuilder {
+            trim_primers: Some(("GATTAG".to_string(), "CCTAAC".to_string())),
+            ..lenient()
+        };
+        let quma = builder.run(GENOME.to_string(), reads).unwrap();
+
+        let reference = &quma.data[0];
+        assert_eq!(
+            (reference.fasta.trim_start, reference.fasta.trim_end),
+            (6, 6)
+        );
+        assert_eq!(reference.fasta.trimmed_seq(), insert);
+        assert_eq!(reference.res.q_start, 0);
+        let start = reference
+            .fasta
+            .original_position(reference.res.q_start as usize);
+        assert_eq!(start, 6);
+        assert_eq!(&reference.fasta.seq[start..start + 10], &insert[..10]);
+    }
+
+    #[test]
+    fn alignment_score_is_reported() {
+        let seq = genome_seq();
+        let matrix = scoring_matrix(None).unwrap();
+        let identity = quma_score(&matrix, b'A', b'A');
+        let mut mutated = seq.clone().into_bytes();
+        // genome G at position 14 read as A, a mismatch on both strands
+        mutated[14] = b'A';
+        let reads = fasta(&[seq.clone(), String::from_utf8(mutated).unwrap()]);
+        let quma = lenient().run(GENOME.to_string(), reads).unwrap();
+
+        let perfect = identity * seq.len() as i32;
+        assert_eq!(quma.data[0].res.score, perfect);
+        let substitution = quma_score(&matrix, b'G', b'A');
+        assert_eq!(quma.data[1].res.score, perfect - identity + substitution);
+    }
+
+    #[test]
+    fn table_has_a_header_and_quoted_csv_comments() {
+        let reads = fasta(&[bisulfite(&genome_seq(), &[true, false, true, false])]);
+        let mut quma = lenient().run(GENOME.to_string(), reads).unwrap();
+        quma.data[0].fasta.com = "read, \"one\"".to_string();
+
+        let tsv = format_table(&quma.data, false);
+        let lines: Vec<&str> = tsv.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0], TABLE_HEADER.join("\t"));
+        let row: Vec<&str> = lines[1].split('\t').collect();
+        assert_eq!(row.len(), TABLE_HEADER.len());
+        assert_eq!(row[13], "1010");
+        assert_eq!(row[16], "8;17;28;40");
+
+        let csv = format_table(&quma.data, true);
+        assert!(csv.starts_with("pos,comment,seq,"));
+        assert!(csv.contains(",\"read, \"\"one\"\"\","));
+    }
+
+    #[test]
+    fn repr_summarizes_the_analysis() {
+        let reads = fasta(&[
+            bisulfite(&genome_seq(), &[true, false, true, false]),
+            bisulfite(&genome_seq(), &[false; 4]),
+        ]);
+        let mut quma = lenient().run(GENOME.to_string(), reads).unwrap();
+        quma.data[1].exc = 1;
+        let res = quma.data[0].res.clone();
+
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let quma = Py::new(py, quma).unwrap();
+            let repr = quma.as_ref(py).repr().unwrap().to_string();
+            assert_eq!(repr, "Quma(reads=2, included=1, genome_length=51)");
+
+            let res = Py::new(py, res).unwrap();
+            let text = res.as_ref(py).str().unwrap().to_string();
+            assert_eq!(
+                text,
+                "QumaResult(perc=100, menum=2, conv=2, unconv=2, val='1010')"
+            );
+        });
+    }
+
+    #[test]
+    fn pickling_restores_results_without_realigning() {
+        let reads = fasta(&[
+            bisulfite(&genome_seq(), &[true, false, true, false]),
+            bisulfite(&genome_seq(), &[false, true, true, true]),
+        ]);
+        let quma = lenient().run(GENOME.to_string(), reads).unwrap();
+        let state = quma.__getstate__().unwrap();
+
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            // importable as it would be from an installed wheel
+            let module = pyo3::wrap_pymodule!(rust_quma)(py);
+            let modules = py.import("sys").unwrap().getattr("modules").unwrap();
+            modules.set_item("rust_quma", module).unwrap();
+
+            let pickle = py.import("pickle").unwrap();
+            let original = Py::new(py, quma).unwrap();
+            let dumped = pickle.call_method1("dumps", (original,)).unwrap();
+            let loaded = pickle.call_method1("loads", (dumped,)).unwrap();
+            let restored: PyRef<Quma> = loaded.extract().unwrap();
+
+            assert_eq!(restored.__getstate__().unwrap(), state);
+            assert_eq!(restored.data[1].res.val, "0111");
+            assert_eq!(restored.pconv_threshold, 100.0);
+        });
+    }
+
+    #[test]
+    fn short_alignments_are_excluded_at_the_threshold() {
+        let seq = genome_seq();
+        let reads = fasta(&[
+            bisulfite(&seq[1..31], &[true, true, true]),
+            bisulfite(&seq[2..31], &[true, true, true]),
+        ]);
+        let builder = QumaBuilder {
+            min_ali_len: 30,
+            ..lenient()
+        };
+        let quma = builder.run(GENOME.to_string(), reads.clone()).unwrap();
+        assert_eq!(quma.data[0].res.ali_len, 30);
+        assert_eq!(
+            (quma.data[0].exc, quma.data[0].reason.as_deref()),
+            (0, None)
+        );
+        assert_eq!(quma.data[1].res.ali_len, 29);
+        assert_eq!(
+            (quma.data[1].exc, quma.data[1].reason.as_deref()),
+            (1, Some("ali_len"))
+        );
+
+        let unfiltered = lenient().run(GENOME.to_string(), reads).unwrap();
+        assert!(unfiltered.data.iter().all(|x| x.exc == 0));
+    }
+
+    #[test]
+    fn genome_cpg_sites_ignore_the_reads() {
+        let reads = fasta(&[bisulfite(&genome_seq()[20..], &[true, true])]);
+        let quma = lenient().run(GENOME.to_string(), reads).unwrap();
+
+        let expected = (4, vec![8, 17, 28, 40]);
+        assert_eq!(quma.genome_cpg_sites(None).unwrap(), expected);
+        assert_eq!(quma.genome_cpg_sites(Some("amplicon")).unwrap(), expected);
+        assert!(quma.genome_cpg_sites(Some("missing")).is_err());
+        assert_eq!(cpg_sites("CGCGAACG"), vec![0, 2, 6]);
+        assert!(cpg_sites("GCATTC").is_empty());
+    }
+
+    #[test]
+    fn conversion_efficiency_counts_non_cpg_cytosines() {
+        let options = QumaBuilder::default().align_options();
+        let contexts = [Context::CpG];
+        // non-CpG cytosines at 1 and 4, CpG at 7
+        let res =
+            process_alignment_matches(alignment("ACATCAACGT", "ATATCAATGT"), &contexts, options);
+        assert_eq!(non_cpg_conversion(&res), (1, 2));
+        assert_eq!((res.conversion_sites, res.conv_eff), (2, 50.0));
+        assert_eq!(res.val, "0");
+
+        // a gapped cytosine is not informative
+        let res =
+            process_alignment_matches(alignment("ACATCAACGT", "A-ATTAACGT"), &contexts, options);
+        assert_eq!((res.conversion_sites, res.conv_eff), (1, 100.0));
+
+        let res =
+            process_alignment_matches(alignment("ATATTAACGT", "ATATTAACGT"), &contexts, options);
+        assert_eq!((res.conversion_sites, res.conv_eff), (0, 0.0));
+    }
+
+    #[test]
+    fn inputs_are_read_from_file_paths() {
+        let reads = fasta(&[
+            bisulfite(&genome_seq(), &[true, false, true, false]),
+            bisulfite(&genome_seq(), &[false, true, true, true]),
+        ]);
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        std::io::Write::write_all(&mut encoder, reads.as_bytes()).unwrap();
+
+        let dir = std::env::temp_dir();
+        let tag = std::process::id();
+        let gpath = dir.join(format!("rust_quma_{}_genome.fa", tag));
+        let qpath = dir.join(format!("rust_quma_{}_reads.fa.gz", tag));
+        fs::write(&gpath, GENOME).unwrap();
+        fs::write(&qpath, encoder.finish().unwrap()).unwrap();
+        let path = |p: &std::path::Path| p.to_string_lossy().into_owned();
+
+        let options = QumaBuilder {
+            contexts: vec![Context::CpG, Context::CHH],
+            ..lenient()
+        };
+        let quma = Quma::from_files(path(&gpath), path(&qpath), None);
+        let configured = Quma::from_files(path(&gpath), path(&qpath), Some(options.clone()));
+        let missing = Quma::from_files(path(&gpath), path(&dir.join("rust_quma_missing.fa")), None);
+        fs::remove_file(&gpath).unwrap();
+        fs::remove_file(&qpath).unwrap();
+
+        let quma = quma.unwrap();
+        let expected = QumaBuilder::default()
+            .run(GENOME.to_string(), reads.clone())
+            .unwrap();
+        assert_eq!(quma.data.len(), 2);
+        assert_eq!(quma.values, expected.values);
+        assert_eq!(quma.data[1].res.val, "0111");
+        assert!(missing.is_err());
+
+        let configured = configured.unwrap();
+        let expected = options.run(GENOME.to_string(), reads).unwrap();
+        assert_eq!(configured.contexts, vec![Context::CpG, Context::CHH]);
+        assert_eq!(configured.values, expected.values);
+        assert!(configured.data[1].res.val.len() > 4);
+    }
+
+    #[test]
+    fn palindromic_reads_align_forward() {
+        let palindrome = "GATTACATGTAATC";
+        assert_eq!(rev_comp(palindrome), palindrome);
+        let genome = format!(">palindrome\n{}\n", palindrome);
+        let reads = fasta(&[palindrome.to_string(), palindrome.to_string()]);
+
+        let quma = lenient().run(genome, reads).unwrap();
+        for reference in &quma.data {
+            assert_eq!(reference.dir, Direction::Forward);
+            assert_eq!(reference.gdir, Direction::Forward);
+            assert_eq!(reference.res.perc, 100.0);
+        }
+    }
+
+    #[test]
+    fn duplicate_reads_are_aligned_once() {
+        let read = bisulfite(&genome_seq(), &[true, false, true, false]);
+        let other = bisulfite(&genome_seq(), &[false; 4]);
+        let reads = fasta(&[read.clone(), other, read.clone(), read]);
+
+        // one progress report per distinct read aligned
+        let mut reports = Vec::new();
+        let mut report = |done, total| reports.push((done, total));
+        let quma = Quma::build(
+            &lenient(),
+            GENOME.to_string(),
+            reads,
+            Some((1, &mut report)),
+        )
+        .unwrap();
+        assert_eq!(reports, vec![(3, 4), (4, 4)]);
+
+        assert_eq!(quma.data.len(), 4);
+        for (i, reference) in quma.data.iter().enumerate() {
+            assert_eq!(reference.fasta.pos, (i + 1).to_string());
+            assert_eq!(reference.fasta.com, format!("r{}", i));
+        }
+        for i in [2, 3] {
+            assert!(quma.data[i].res == quma.data[0].res);
+        }
+        assert_eq!(quma.data[1].res.val, "0000");
+    }
+
+    // logger keeping every record, for asserting on emitted messages
+    struct CapturingLogger {
+        records: std::sync::Mutex<Vec<(log::Level, String)>>,
+    }
+
+    impl log::Log for CapturingLogger {
+        fn enabled(&self, _metadata: &log::Metadata) -> bool {
+            return true;
+        }
+
+        fn log(&self, record: &log::Record) {
+            let message = (record.level(), record.args().to_string());
+            self.records.lock().unwrap().push(message);
+        }
+
+        fn flush(&self) {}
+    }
+
+    static LOGGER: CapturingLogger = CapturingLogger {
+        records: std::sync::Mutex::new(Vec::new()),
+    };
+
+    #[test]
+    fn unaligned_reads_are_logged() {
+        // other tests may have installed it already
+        let _ = log::set_logger(&LOGGER);
+        log::set_max_level(log::LevelFilter::Debug);
+
+        let reads = format!(">logged_hit\n{}\n>logged_miss\nNNNNNNNNNN\n", genome_seq());
+        let builder = QumaBuilder {
+            min_score: Some(1),
+            ..lenient()
+        };
+        let quma = builder.run(GENOME.to_string(), reads).unwrap();
+        assert_eq!(quma.data[1].res.ali_len, 0);
+
+        let records = LOGGER.records.lock().unwrap();
+        let logged = |level, text: &str| records.iter().any(|(l, m)| *l == level && m == text);
+        assert!(logged(
+            log::Level::Warn,
+            "read logged_miss did not align to the genome"
+        ));
+        assert!(!logged(
+            log::Level::Warn,
+            "read logged_hit did not align to the genome"
+        ));
+        assert!(logged(
+            log::Level::Debug,
+            "read logged_miss excluded: unaligned"
+        ));
+        assert!(records
+            .iter()
+            .any(|(l, m)| *l == log::Level::Debug && m.starts_with("read logged_hit: dir 1")));
+    }
+
+    #[test]
+    fn heatmap_marks_uncovered_sites() {
+        let seq = genome_seq();
+        let reads = fasta(&[
+            bisulfite(&seq, &[true, false, true, false]),
+            bisulfite(&seq[..32], &[false, true, true]),
+            bisulfite(&seq, &[true; 4]),
+        ]);
+        let mut quma = lenient().run(GENOME.to_string(), reads).unwrap();
+        quma.data[2].exc = 1;
+
+        let (rows, columns) = quma.methylation_heatmap(None).unwrap();
+        assert_eq!(columns, vec![8, 17, 28, 40]);
+        assert_eq!(
+            rows,
+            vec![vec![1, 0, 1, 0], vec![0, 1, 1, HEATMAP_NOT_COVERED]]
+        );
+        assert!(quma.methylation_heatmap(Some("missing")).is_err());
+    }
+
+    #[test]
+    fn lowercase_reads_are_analyzed_like_uppercase() {
+        let read = bisulfite(&genome_seq(), &[true, false, false, true]);
+        let upper = lenient()
+            .run(GENOME.to_string(), fasta(std::slice::from_ref(&read)))
+            .unwrap();
+        let lower = lenient()
+            .run(GENOME.to_string(), fasta(&[read.to_lowercase()]))
+            .unwrap();
+
+        assert_eq!(lower.data[0].fasta.seq, read);
+        assert_eq!(lower.data[0].res.val, "1001");
+        assert!(lower.data[0].res == upper.data[0].res);
+        assert_eq!(lookup(b'g'), lookup(b'G'));
+        assert_eq!(lookup(b'n'), Some(N_INDEX));
+    }
+
+    #[test]
+    fn thread_count_sizes_a_local_pool() {
+        assert!(thread_pool(None).unwrap().is_none());
+        let pool = thread_pool(Some(3)).unwrap().unwrap();
+        assert_eq!(pool.current_num_threads(), 3);
+
+        let reads = fasta(&[
+            bisulfite(&genome_seq(), &[true, false, true, false]),
+            bisulfite(&genome_seq(), &[false, true, false, true]),
+        ]);
+        let global = lenient().run(GENOME.to_string(), reads.clone()).unwrap();
+        let builder = QumaBuilder {
+            num_threads: Some(2),
+            ..lenient()
+        };
+        let local = builder.run(GENOME.to_string(), reads).unwrap();
+        assert_eq!(global.values, local.values);
+        assert_eq!(local.num_threads, Some(2));
+    }
+
+    #[test]
+    fn midline_marks_matches_conversions_and_mismatches() {
+        let options = QumaBuilder::default().align_options();
+        let res = process_alignment_matches(
+            alignment("ACGTCA-GA", "ATGTTAAGC"),
+            &[Context::CpG],
+            options,
+        );
+        assert_eq!(res.midline, "|:||:| | ");
+        assert_eq!(res.midline.len(), res.q_ali.len());
+        assert_eq!(alignment_midline(b"CT-", b"CTA"), "|| ");
+        assert_eq!(alignment_midline(b"TG", b"CA"), "  ");
+    }
+
+    #[test]
+    fn wrapped_fasta_keeps_the_whole_sequence() {
+        let seq = genome_seq();
+        let long = format!("{}{}", seq, seq.chars().rev().collect::<String>());
+        let wrapped = fasta_make(&long, "long");
+        let lengths: Vec<usize> = wrapped.lines().map(|x| x.len()).collect();
+        assert_eq!(lengths, vec![5, FASTA_WIDTH, long.len() - FASTA_WIDTH]);
+        assert_eq!(fasta_sequence(&wrapped), long);
+
+        let genome = format!("{}\n", wrapped);
+        let quma = lenient()
+            .run(genome, fasta(std::slice::from_ref(&long)))
+            .unwrap();
+        assert_eq!(quma.data[0].res.ali_len, long.len() as i32);
+        assert_eq!(quma.data[0].res.perc, 100.0);
+    }
+
+    #[test]
+    fn percentage_is_none_without_a_denominator() {
+        assert_eq!(percentage(1, 3, PercentType::Sum), Some(25.0));
+        assert_eq!(percentage(1, 4, PercentType::Total), Some(25.0));
+        assert_eq!(percentage(0, 0, PercentType::Sum), None);
+        assert_eq!(percentage(3, 0, PercentType::Total), None);
+
+        // an empty alignment reports zeros rather than NaN
+        let options = QumaBuilder::default().align_options();
+        let res = process_alignment_matches(alignment("", ""), &[Context::CpG], options);
+        assert_eq!((res.perc, res.pconv, res.conv_eff), (0.0, 0.0, 0.0));
+        assert_eq!(res.methylation_level(), 0.0);
+    }
+
+    #[test]
+    fn reverse_reads_of_the_reverse_strand_align_to_it() {
+        let bottom = rev_comp(&genome_seq());
+        let converted = bisulfite(&bottom, &[false, false, true, true]);
+        let reads = fasta(&[rev_comp(&converted)]);
+        let quma = lenient().run(GENOME.to_string(), reads).unwrap();
+
+        let reference = &quma.data[0];
+        assert_eq!((reference.dir.sign(), reference.gdir.sign()), (-1, -1));
+        assert_eq!(reference.res.q_ali, converted);
+        assert_eq!(reference.res.val, "0011");
+        assert_eq!(reference.res.cpg_positions, vec![40, 28, 17, 8]);
+        assert_eq!(reference.res.perc, 100.0);
+    }
+
+    #[test]
+    fn included_and_excluded_partition_the_reads() {
+        let seq = genome_seq();
+        let reads = fasta(&[
+            bisulfite(&seq, &[true, false, true, false]),
+            bisulfite(&seq[..20], &[true, true]),
+            bisulfite(&seq, &[false; 4]),
+            bisulfite(&seq[5..25], &[true, true]),
+        ]);
+        let builder = QumaBuilder {
+            min_ali_len: 30,
+            ..lenient()
+        };
+        let quma = builder.run(GENOME.to_string(), reads).unwrap();
+
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let quma = Py::new(py, quma).unwrap();
+            let names = |method: &str| -> Vec<String> {
+                let references: Vec<Reference> =
+                    quma.call_method0(py, method).unwrap().extract(py).unwrap();
+                return references.into_iter().map(|x| x.fasta.com).collect();
+            };
+            assert_eq!(names("included"), vec!["r0", "r2"]);
+            assert_eq!(names("excluded"), vec!["r1", "r3"]);
+        });
+    }
+
+    #[test]
+    fn builder_setters_chain_from_python() {
+        let reads = fasta(&[bisulfite(&genome_seq(), &[true, false, true, false])]);
+
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let locals = pyo3::types::PyDict::new(py);
+            locals
+                .set_item("QumaBuilder", py.get_type::<QumaBuilder>())
+                .unwrap();
+            locals
+                .set_item("AlignMode", py.get_type::<AlignMode>())
+                .unwrap();
+            locals
+                .set_item("Context", py.get_type::<Context>())
+                .unwrap();
+            locals.set_item("genome", GENOME).unwrap();
+            locals.set_item("reads", reads).unwrap();
+            let quma = py
+                .eval(
+                    "QumaBuilder()\
+                     .with_thresholds(pconv=100.0, percent=100.0)\
+                     .with_gap_penalties(-7, -2)\
+                     .with_mode(AlignMode.Global)\
+                     .with_context([Context.CpG, Context.CHH])\
+                     .with_bisulfite_scoring(True)\
+                     .build(genome, reads)",
+                    None,
+                    Some(locals),
+                )
+                .unwrap();
+            let quma: PyRef<Quma> = quma.extract().unwrap();
+
+            assert_eq!(
+                (quma.pconv_threshold, quma.percent_threshold),
+                (100.0, 100.0)
+            );
+            assert_eq!(
+                quma.unconv_threshold,
+                QumaBuilder::default().unconv_threshold
+            );
+            assert_eq!((quma.gap_open, quma.gap_extend), (-7, -2));
+            assert!(quma.align_mode == AlignMode::Global);
+            assert_eq!(quma.contexts, vec![Context::CpG, Context::CHH]);
+            assert!(quma.bisulfite_scoring);
+            assert_eq!(quma.data[0].exc, 0);
+            assert_eq!(quma.data[0].res.score, 5 * genome_seq().len() as i32);
+        });
+    }
+
+    #[test]
+    fn methylation_level_is_the_methylated_fraction() {
+        let reads = fasta(&[
+            bisulfite(&genome_seq(), &[true, true, true, false]),
+            bisulfite(&genome_seq()[20..], &[false, true]),
+        ]);
+        let quma = lenient().run(GENOME.to_string(), reads).unwrap();
+        let res = &quma.data[0].res;
+        assert_eq!((res.conv, res.unconv), (3, 1));
+        assert_eq!(res.methylation_level(), 75.0);
+        assert_eq!(quma.data[1].res.methylation_level(), 50.0);
+
+        let empty = process_alignment_matches(
+            alignment("TTAGA", "TTAGA"),
+            &[Context::CpG],
+            QumaBuilder::default().align_options(),
+        );
+
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let level = |res: QumaResult| -> f32 {
+                let res = Py::new(py, res).unwrap();
+                return res
+                    .getattr(py, "methylation_level")
+                    .unwrap()
+                    .extract(py)
+                    .unwrap();
+            };
+            assert_eq!(level(quma.data[0].res.clone()), 75.0);
+            assert_eq!(level(empty), 0.0);
+        });
+    }
+
+    #[test]
+    fn identical_runs_give_equal_results() {
+        let reads = fasta(&[
+            bisulfite(&genome_seq(), &[true, false, true, false]),
+            bisulfite(&genome_seq(), &[false, true, true, true]),
+        ]);
+        let first = lenient().run(GENOME.to_string(), reads.clone()).unwrap();
+        let second = lenient().run(GENOME.to_string(), reads).unwrap();
+
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let wrap = |quma: &Quma, i: usize| {
+                let res = Py::new(py, quma.data[i].res.clone()).unwrap();
+                let fa = Py::new(py, quma.data[i].fasta.clone()).unwrap();
+                return (res.into_ref(py), fa.into_ref(py));
+            };
+            let (res_a, fa_a) = wrap(&first, 0);
+            let (res_b, fa_b) = wrap(&second, 0);
+            let (res_other, fa_other) = wrap(&second, 1);
+
+            assert!(res_a.eq(res_b).unwrap());
+            assert!(res_a.ne(res_other).unwrap());
+            assert_eq!(res_a.hash().unwrap(), res_b.hash().unwrap());
+            assert!(fa_a.eq(fa_b).unwrap());
+            assert!(fa_a.ne(fa_other).unwrap());
+            assert_eq!(fa_a.hash().unwrap(), fa_b.hash().unwrap());
+
+            // usable as set members
+            let set = pyo3::types::PySet::new(py, &[res_a, res_b, res_other]).unwrap();
+            assert_eq!(set.len(), 2);
+        });
+    }
+
+    #[test]
+    fn streamed_reads_match_the_batch_analysis() {
+        let patterns: Vec<String> = (0..1000)
+            .map(|i| {
+                let methylated: Vec<bool> = (0..4).map(|bit| i >> bit & 1 == 1).collect();
+                bisulfite(&genome_seq(), &methylated)
+            })
+            .collect();
+        let reads = fasta(&patterns);
+        let path = std::env::temp_dir().join(format!("rust_quma_{}_stream.fa", std::process::id()));
+        fs::write(&path, &reads).unwrap();
+
+        let chunk_size = 64;
+        let stream = Quma::stream(
+            GENOME,
+            path.to_string_lossy().into_owned(),
+            Some(lenient()),
+            chunk_size,
+        );
+        let mut stream = stream.unwrap();
+        let mut streamed = Vec::<Reference>::new();
+        while let Some(reference) = stream.__next__().unwrap() {
+            // at most one chunk of reads is parsed ahead of those yielded
+            assert!(stream.pending.len() < chunk_size);
+            assert!(stream.reads <= streamed.len() + chunk_size);
+            streamed.push(reference);
+        }
+        fs::remove_file(&path).unwrap();
+
+        let batch = lenient().run(GENOME.to_string(), reads).unwrap();
+        assert_eq!(streamed.len(), batch.data.len());
+        for (stream_ref, batch_ref) in streamed.iter().zip(&batch.data) {
+            assert_eq!(stream_ref.fasta.com, batch_ref.fasta.com);
+            assert!(stream_ref.res == batch_ref.res);
+            assert_eq!(stream_ref.exc, batch_ref.exc);
+        }
+    }
+
+    #[test]
+    fn stray_genome_characters_are_reported() {
+        let seq = genome_seq();
+        let genome = format!(
+            ">amplicon\n{}@{}\n{}1{}\n",
+            &seq[..10],
+            &seq[10..25],
+            &seq[25..40],
+            &seq[40..]
+        );
+        let expected = vec![(2, 11, '@'), (3, 16, '1')];
+        assert_eq!(check_alphabet(&genome, None).unwrap(), expected);
+        assert!(check_alphabet(GENOME, None).unwrap().is_empty());
+
+        let reads = fasta(&[bisulfite(&seq, &[true, false, true, false])]);
+        let strict = QumaBuilder {
+            strict_alphabet: true,
+            ..lenient()
+        };
+        match strict.run(genome.clone(), reads.clone()) {
+            Err(QumaError::DisallowedCharacters(found)) => assert_eq!(found, expected),
+            _ => panic!("stray characters were accepted"),
+        }
+
+        // otherwise the characters are filtered out and recorded
+        let quma = lenient().run(genome, reads).unwrap();
+        assert_eq!(quma.filtered_characters, expected);
+        assert_eq!(quma.gseq, seq);
+        assert_eq!(quma.data[0].res.val, "1010");
+    }
+
+    #[test]
+    fn sam_cigar_reports_insertions_and_deletions() {
+        // two soft-clipped bases, then an insertion and a deletion
+        let mut res = alignment("ACGT-ACGTA", "ACGTTAC-TA");
+        res.q_start = 2;
+        assert_eq!(
+            cigar_operations(&res, 12, (0, 0)),
+            vec![
+                (2, 'S'),
+                (4, 'M'),
+                (1, 'I'),
+                (2, 'M'),
+                (1, 'D'),
+                (2, 'M'),
+                (1, 'S')
+            ]
+        );
+        assert_eq!(cigar_operations(&res, 12, (3, 1)).first(), Some(&(3, 'H')));
+
+        let reads = ">read1 sample\nGGACGTTACTAC\n".to_string();
+        let mut quma = lenient()
+            .run(">ref\nTTACGTACGTAGG\n".to_string(), reads)
+            .unwrap();
+        res.g_start = 2;
+        res.ali_len = 10;
+        quma.data[0].res = res;
+        quma.data[0].dir = Direction::Forward;
+        quma.data[0].gdir = Direction::Forward;
+        quma.data[0].exc = 0;
+
+        let sam = quma.to_sam();
+        let lines: Vec<&str> = sam.lines().collect();
+        assert_eq!(lines[0], "@HD\tVN:1.6\tSO:unsorted");
+        assert_eq!(lines[1], "@SQ\tSN:ref\tLN:13");
+        let record: Vec<&str> = lines[2].split('\t').collect();
+        assert_eq!(
+            &record[..6],
+            &["read1", "0", "ref", "3", "255", "2S4M1I2M1D2M1S"]
+        );
+        assert_eq!(record[9], "GGACGTTACTAC");
+    }
+
+    #[test]
+    fn progress_callback_counts_reads_from_python() {
+        let patterns: Vec<String> = (0..5)
+            .map(|i| {
+                let methylated: Vec<bool> = (0..4).map(|bit| i >> bit & 1 == 1).collect();
+                bisulfite(&genome_seq(), &methylated)
+            })
+            .collect();
+        let reads = fasta(&patterns);
+
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let calls = pyo3::types::PyList::empty(py);
+            let globals = pyo3::types::PyDict::new(py);
+            globals.set_item("calls", calls).unwrap();
+            let record = "lambda done, total: calls.append((done, total))";
+            let record = py.eval(record, Some(globals), None).unwrap();
+            let kwargs = pyo3::types::PyDict::new(py);
+            kwargs.set_item("progress", record).unwrap();
+            kwargs.set_item("progress_every", 2).unwrap();
+            kwargs.set_item("num_threads", 4).unwrap();
+            py.get_type::<Quma>()
+                .call((GENOME, reads), Some(kwargs))
+                .unwrap();
+
+            let calls: Vec<(usize, usize)> = calls.extract().unwrap();
+            assert_eq!(calls, vec![(2, 5), (4, 5), (5, 5)]);
+
+            // errors raised by the callback stop the analysis
+            let failing = py.eval("lambda done, total: 1 / 0", None, None).unwrap();
+            kwargs.set_item("progress", failing).unwrap();
+            let reads = fasta(&patterns);
+            let err = py.get_type::<Quma>().call((GENOME, reads), Some(kwargs));
+            assert!(err
+                .unwrap_err()
+                .is_instance_of::<pyo3::exceptions::PyZeroDivisionError>(py));
+        });
+    }
+
+    #[test]
+    fn direction_enum_follows_the_read_orientation() {
+        let read = bisulfite(&genome_seq(), &[true, false, true, false]);
+        let reads = fasta(&[read.clone(), rev_comp(&read)]);
+        let quma = lenient().run(GENOME.to_string(), reads).unwrap();
+        assert_eq!(quma.data[0].dir, Direction::Forward);
+        assert_eq!(quma.data[1].dir, Direction::Reverse);
+        assert_eq!(quma.data[1].res.val, "1010");
+
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let reference = Py::new(py, quma.data[1].clone()).unwrap();
+            let dir = reference.getattr(py, "dir").unwrap();
+            let reverse = py.get_type::<Direction>().getattr("Reverse").unwrap();
+            assert!(dir.as_ref(py).eq(reverse).unwrap());
+            let sign: i32 = dir.call_method0(py, "sign").unwrap().extract(py).unwrap();
+            assert_eq!(sign, -1);
+            let gdir: Direction = reference.getattr(py, "gdir").unwrap().extract(py).unwrap();
+            assert_eq!(gdir.sign(), 1);
+        });
+    }
+
+    #[test]
+    fn reused_aligner_matches_a_fresh_one() {
+        let builder = QumaBuilder {
+            clip_penalty: Some(-5),
+            ..lenient()
+        };
+        let options = builder.align_options();
+        let matrix = scoring_matrix(None).unwrap();
+        let genome = fasta_make(&genome_seq(), "genome");
+        let seq = genome_seq();
+        // reads of different lengths resize the aligner's buffers
+        let reads = [
+            bisulfite(&seq, &[true, false, true, false]),
+            bisulfite(&seq[10..30], &[true, false]),
+            format!("{}GGGGGGGGGGGG", bisulfite(&seq, &[false; 4])),
+            bisulfite(&seq[20..], &[true, true]),
+        ];
+
+        let mut reused = read_aligner(options, &matrix);
+        for read in reads.iter().chain(reads.iter().rev()) {
+            let qfile = fasta_make(read, "read");
+            let mut fresh = read_aligner(options, &matrix);
+            let expected = align_seq_and_generate_stats(
+                &qfile,
+                &genome,
+                &builder.contexts,
+                options,
+                &mut fresh,
+            );
+            let actual = align_seq_and_generate_stats(
+                &qfile,
+                &genome,
+                &builder.contexts,
+                options,
+                &mut reused,
+            );
+            assert!(actual.unwrap() == expected.unwrap());
+        }
+    }
+
+    #[test]
+    fn directional_reads_skip_the_reverse_complement() {
+        let read = rev_comp(&bisulfite(&genome_seq(), &[true, false, true, false]));
+        let reads = fasta(std::slice::from_ref(&read));
+
+        let both = lenient().run(GENOME.to_string(), reads.clone()).unwrap();
+        assert_eq!(both.data[0].dir, Direction::Reverse);
+        assert_eq!(both.data[0].res.perc, 100.0);
+
+        let builder = QumaBuilder {
+            check_reverse: false,
+            ..lenient()
+        };
+        let directional = builder.run(GENOME.to_string(), reads).unwrap();
+        assert_eq!(directional.data[0].dir, Direction::Forward);
+        assert!(directional.data[0].res.perc < 100.0);
+        assert!(!directional.check_reverse);
+    }
+
+    #[test]
+    fn table_positions_share_genome_coordinates_across_reads() {
+        let seq = genome_seq();
+        let reads = fasta(&[
+            bisulfite(&seq[..32], &[true, false, true]),
+            bisulfite(&seq[15..], &[false, true, true]),
+        ]);
+        let quma = lenient().run(GENOME.to_string(), reads).unwrap();
+
+        let table = format_table(&quma.data, false);
+        let rows: Vec<Vec<&str>> = table
+            .lines()
+            .skip(1)
+            .map(|x| x.split('\t').collect())
+            .collect();
+        assert_eq!((rows[0][13], rows[0][16]), ("101", "8;17;28"));
+        assert_eq!((rows[1][13], rows[1][16]), ("011", "17;28;40"));
+        // the alignments start at different offsets but share CpG labels
+        assert_ne!(quma.data[0].res.g_start, quma.data[1].res.g_start);
+    }
+
+    #[test]
+    fn cpg_free_windows_are_flagged() {
+        let seq = genome_seq();
+        // 19..28 lies between the CpGs at 17 and 28
+        let window = &seq[19..28];
+        let reads = fasta(&[bisulfite(window, &[]), bisulfite(&seq, &[false; 4])]);
+        let quma = lenient().run(GENOME.to_string(), reads).unwrap();
+
+        let res = &quma.data[0].res;
+        assert_eq!(res.ali_len, window.len() as i32);
+        assert!(res.no_cpg);
+        assert_eq!((res.menum, res.val.as_str()), (0, "-"));
+
+        let res = &quma.data[1].res;
+        assert!(!res.no_cpg);
+        assert_eq!((res.menum, res.val.as_str()), (0, "0000"));
+    }
+
+    #[test]
+    fn custom_scoring_matrix_changes_the_score() {
+        let doubled: Vec<i32> = MATRIX.iter().map(|x| x * 2).collect();
+        let reads = fasta(&[genome_seq()]);
+        let default = lenient().run(GENOME.to_string(), reads.clone()).unwrap();
+        let builder = QumaBuilder {
+            scoring_matrix: Some(doubled.clone()),
+            ..lenient()
+        };
+        let custom = builder.run(GENOME.to_string(), reads.clone()).unwrap();
+
+        assert_eq!(custom.scoring_matrix, doubled);
+        assert_eq!(
+            default.scoring_matrix,
+            MATRIX.iter().copied().collect::<Vec<i32>>()
+        );
+        assert_eq!(custom.data[0].res.score, 2 * default.data[0].res.score);
+        assert_eq!(custom.data[0].res.q_ali, default.data[0].res.q_ali);
+
+        let short = QumaBuilder {
+            scoring_matrix: Some(vec![1; 10]),
+            ..lenient()
+        };
+        assert!(matches!(
+            short.run(GENOME.to_string(), reads),
+            Err(QumaError::MatrixSize(10))
+        ));
+    }
+
+    #[test]
+    fn restricted_alphabet_filters_other_characters() {
+        let seq = genome_seq();
+        let genome = format!(">amplicon\n{}N{}\n", &seq[..30], &seq[30..]);
+        let reads = fasta(&[bisulfite(&seq, &[true, false, true, false])]);
+        let builder = QumaBuilder {
+            alphabet: Some("ACGT".to_string()),
+            ..lenient()
+        };
+        let quma = builder.run(genome.clone(), reads.clone()).unwrap();
+        assert_eq!(quma.alphabet, "ACGT");
+        assert_eq!(quma.filtered_characters, vec![(2, 31, 'N')]);
+        assert_eq!(quma.gseq, seq);
+        assert_eq!(quma.data[0].res.val, "1010");
+
+        // the default alphabet keeps N
+        let quma = lenient().run(genome.clone(), reads.clone()).unwrap();
+        assert!(quma.filtered_characters.is_empty());
+        assert_eq!(quma.gseq.len(), seq.len() + 1);
+
+        let empty = QumaBuilder {
+            alphabet: Some(String::new()),
+            ..lenient()
+        };
+        assert!(matches!(
+            empty.run(genome, reads),
+            Err(QumaError::EmptyAlphabet)
+        ));
+    }
+
+    #[test]
+    fn duplicates_are_counted_once_in_either_orientation() {
+        let first = bisulfite(&genome_seq(), &[true, false, true, false]);
+        let second = bisulfite(&genome_seq(), &[false; 4]);
+        let reads = fasta(&[
+            first.clone(),
+            first.clone(),
+            rev_comp(&first),
+            second.clone(),
+            second,
+        ]);
+        let mut quma = lenient().run(GENOME.to_string(), reads).unwrap();
+        assert_eq!(quma.data[2].dir, Direction::Reverse);
+
+        assert_eq!(quma.unique_count(), 2);
+        assert_eq!(quma.duplication_rate(), 60.0);
+
+        // excluded reads are not molecules
+        quma.data[3].exc = 1;
+        quma.data[4].exc = 1;
+        assert_eq!(quma.unique_count(), 1);
+        assert_eq!(quma.duplication_rate(), 100.0 * 2.0 / 3.0);
+        assert_eq!(unique_count(&[]), 0);
+    }
+
+    #[test]
+    fn terminal_gap_columns_are_trimmed() {
+        let (g_ali, q_ali, g_skipped, q_skipped) = trim_terminal_gaps("--ACGT-", "AAAC-TG");
+        assert_eq!((g_ali.as_str(), q_ali.as_str()), ("ACGT", "AC-T"));
+        assert_eq!((g_skipped, q_skipped), (0, 2));
+
+        let (g_ali, q_ali, g_skipped, q_skipped) = trim_terminal_gaps("TTCGA", "--CG-");
+        assert_eq!((g_ali.as_str(), q_ali.as_str()), ("CG", "CG"));
+        assert_eq!((g_skipped, q_skipped), (2, 0));
+        assert_eq!(
+            trim_terminal_gaps("--", "AA"),
+            (String::new(), String::new(), 0, 2)
+        );
+
+        let options = QumaBuilder::default().align_options();
+        let padded =
+            process_alignment_matches(alignment("-TCGA--", "ATCGATT"), &[Context::CpG], options);
+        let (g_ali, q_ali, _, _) = trim_terminal_gaps("-TCGA--", "ATCGATT");
+        let trimmed =
+            process_alignment_matches(alignment(&g_ali, &q_ali), &[Context::CpG], options);
+        assert_eq!((padded.ali_len, padded.gap), (7, 3));
+        assert_eq!((trimmed.ali_len, trimmed.gap), (4, 0));
+        assert_eq!((trimmed.perc, trimmed.val.as_str()), (100.0, "1"));
+        assert_eq!(trimmed.cpg_positions, vec![1]);
+    }
+
+    #[test]
+    fn mismatch_positions_leave_out_conversions() {
+        let options = QumaBuilder::default().align_options();
+        // C read as T at column 1, T read as G at column 4
+        let res =
+            process_alignment_matches(alignment("ACCGTA", "ATCGGA"), &[Context::CpG], options);
+        assert_eq!(res.mismatch_positions, vec![4]);
+        assert_eq!((res.quma_match, res.ali_mis), (5, 1));
+
+        // gaps and compatible ambiguity codes are not mismatches
+        assert!(mismatch_columns(b"A-GT", b"ACG-", true).is_empty());
+        assert_eq!(mismatch_columns(b"ARG", b"AGG", true), Vec::<i32>::new());
+        assert_eq!(mismatch_columns(b"ARG", b"AGG", false), vec![1]);
+    }
+
+    #[test]
+    fn every_column_counts_once_toward_matches() {
+        let options = QumaBuilder::default().align_options();
+        // column: 0123456789012
+        // genome: ACGTTCGACCA-T
+        // read:   ACGTTTGACTGAT
+        // CpGs at 1 (read C) and 5 (read T); C read as T at 5 and 9 are
+        // conversions; A read as G at 10 is a mismatch; 11 is a gap
+        let res = process_alignment_matches(
+            alignment("ACGTTCGACCA-T", "ACGTTTGACTGAT"),
+            &[Context::CpG],
+            options,
+        );
+        assert_eq!(res.val, "10");
+        assert_eq!((res.conv, res.unconv), (1, 1));
+        assert_eq!((res.ali_len, res.quma_match, res.ali_mis), (13, 11, 2));
+        assert_eq!(res.perc, 100.0 * 11.0 / 13.0);
+        assert_eq!(res.pconv, 50.0);
+
+        // a fully converted read matches at every column, no more
+        let read = bisulfite(&genome_seq(), &[false; 4]);
+        let quma = lenient().run(GENOME.to_string(), fasta(&[read])).unwrap();
+        let res = &quma.data[0].res;
+        assert_eq!((res.ali_len, res.quma_match, res.ali_mis), (51, 51, 0));
+        assert_eq!(res.perc, 100.0);
+    }
+
+    #[test]
+    fn pooled_and_mean_methylation_differ_on_imbalanced_reads() {
+        let seq = genome_seq();
+        let reads = fasta(&[
+            bisulfite(&seq, &[true; 4]),
+            bisulfite(&seq[30..], &[false]),
+            bisulfite(&seq[19..28], &[]),
+        ]);
+        let mut quma = lenient().run(GENOME.to_string(), reads).unwrap();
+        assert!(quma.data[2].res.no_cpg);
+
+        // four methylated of five sites, against one fully and one
+        // unmethylated read; the CpG-free read is left out of the mean
+        assert_eq!(quma.methylation_levels(), (80.0, 50.0));
+
+        for reference in quma.data.iter_mut() {
+            reference.exc = 1;
+        }
+        assert_eq!(quma.methylation_levels(), (0.0, 0.0));
+    }
+
+    #[test]
+    fn recall_methylation_reapplies_exclusion() {
+        let seq = "TTAGCAGATTACGTTAGCTGAATTCGATTAGGATAT";
+        let reads = fasta(&[
+            bisulfite(seq, &[true, true]),
+            bisulfite(&seq[..20], &[true]),
+        ]);
+        let builder = QumaBuilder {
+            unconv_threshold: 1,
+            expected_length: Some(seq.len()),
+            ..lenient()
+        };
+        let mut quma = builder.run(format!(">chg\n{}\n", seq), reads).unwrap();
+        let matches = quma.data[0].res.quma_match;
+        assert_eq!((quma.data[0].exc, quma.data[0].res.unconv), (0, 0));
+        assert_eq!(quma.data[1].reason.as_deref(), Some("length"));
+
+        // both CHG cytosines are converted
+        quma.recall_methylation(vec![Context::CHG]);
+        assert_eq!(quma.data[0].res.unconv, 2);
+        assert_eq!(quma.data[0].reason.as_deref(), Some("unconv"));
+        assert_eq!(quma.data[0].res.quma_match, matches);
+        assert_eq!(quma.data[1].reason.as_deref(), Some("length"));
+
+        quma.recall_methylation(vec![Context::CpG]);
+        assert_eq!(
+            (quma.data[0].exc, quma.data[0].reason.as_deref()),
+            (0, None)
+        );
+        assert_eq!(quma.data[1].exc, 1);
+    }
+
+    #[test]
+    fn long_table_sites_follow_the_call_positions() {
+        let read = format!(">read\n{}\n", rev_comp(&genome_seq()));
+        let builder = QumaBuilder {
+            contexts: vec![Context::CpG, Context::CHH],
+            ..lenient()
+        };
+        let quma = builder.run(GENOME.to_string(), read).unwrap();
+        let res = &quma.data[0].res;
+        assert_eq!(quma.data[0].gdir, Direction::Reverse);
+        assert_eq!(res.cpg_positions.len(), res.val.len());
+
+        let sites: Vec<i32> = quma
+            .long_table()
+            .lines()
+            .skip(1)
+            .map(|row| row.split('\t').nth(3).unwrap().parse().unwrap())
+            .collect();
+        assert_eq!(sites, vec![40, 28, 17, 8]);
+        assert!(sites.iter().all(|x| res.cpg_positions.contains(x)));
+    }
+}